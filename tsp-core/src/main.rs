@@ -43,14 +43,19 @@ fn main() {
     let _and_then = AndThenPattern::new(assert.clone(), window);
 
     let projection = FirstProjection::new(|e: &&TestEvent| e.value);
+    let config = tsp::config::EngineConfig::builder()
+        .max_chunk_size(10)
+        .build()
+        .expect("Invalid config");
     let state_machine = tsp::query::SimpleMachineMapper::new(
         projection,
         function.clone(),
         FunctionPartitioner::new(|e: &TestEvent| e.idx),
-    );
+    )
+    .with_config(config);
     // tsp::tsp::SimpleMachineMapper::new(constant_pattern);
 
-    let iter = state_machine.run(ints.iter(), 10);
+    let iter = state_machine.run(ints.iter());
     {
         for x in iter {
             println!("{:?}", x)
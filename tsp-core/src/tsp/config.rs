@@ -0,0 +1,277 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Which buffered partition is flushed as a partial chunk when one of the buffer limits of
+/// `EngineConfig` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum FlushPolicy {
+    /// The partition with the most buffered events, so the chunks stay as big as possible. The
+    /// oldest one is chosen among partitions of the same size.
+    #[default]
+    Largest,
+    /// The partition which has been buffering events for the longest time.
+    Oldest,
+}
+
+/// Order in which the query returns incidents.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum OutputOrdering {
+    /// Incidents are returned as soon as they are computed.
+    #[default]
+    Completion,
+    /// Incidents are grouped by partitions in order of their first events, so all incidents of
+    /// a partition but the first one are kept until the end of the input.
+    InsertionOrder,
+    /// Incidents are sorted by the position of their first event in the whole input. An
+    /// incident is kept until all events before its start are resolved by the rule.
+    ByStart,
+}
+
+/// What happens with the events of an idle partition which are not resolved by the rule yet
+/// when the partition is evicted.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// The events are returned as an incident with `PatternResult::Unknown` result, unless
+    /// `UnknownPolicy::Skip` is used.
+    #[default]
+    Close,
+    /// The events are dropped together with the state of the partition.
+    Drop,
+}
+
+/// What happens with the event which comes behind the watermark when the events are reordered
+/// by `SimpleMachineMapper::with_event_time`. Such events may be observed by
+/// `SimpleMachineMapper::with_on_late_event` with either policy.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum LateEventPolicy {
+    #[default]
+    Drop,
+    /// The event is passed on immediately out of the timestamp order.
+    Reprocess,
+}
+
+/// Options of the query engine, is created by `EngineConfig::builder()` which validates them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EngineConfig {
+    max_chunk_size: usize,
+    max_buffered_events: usize,
+    max_buffered_partitions: usize,
+    flush_policy: FlushPolicy,
+    output_ordering: OutputOrdering,
+    max_idle_events: Option<u64>,
+    max_idle_time: Option<Duration>,
+    eviction_policy: EvictionPolicy,
+    allowed_lateness: u64,
+    reorder_idle_timeout: Option<u64>,
+    late_event_policy: LateEventPolicy,
+}
+
+impl EngineConfig {
+    pub fn builder() -> EngineConfigBuilder {
+        EngineConfigBuilder {
+            config: EngineConfig::default(),
+        }
+    }
+
+    /// Maximum number of events of one partition passed to patterns at once.
+    pub fn max_chunk_size(&self) -> usize {
+        self.max_chunk_size
+    }
+
+    /// Maximum number of events buffered for all partitions.
+    pub fn max_buffered_events(&self) -> usize {
+        self.max_buffered_events
+    }
+
+    /// Maximum number of partitions with buffered events.
+    pub fn max_buffered_partitions(&self) -> usize {
+        self.max_buffered_partitions
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    pub fn output_ordering(&self) -> OutputOrdering {
+        self.output_ordering
+    }
+
+    /// A partition is evicted when this number of events of other partitions come after its
+    /// last event, partitions are kept forever if neither this nor `max_idle_time` is set.
+    pub fn max_idle_events(&self) -> Option<u64> {
+        self.max_idle_events
+    }
+
+    /// A partition is evicted when it has no events for this time.
+    pub fn max_idle_time(&self) -> Option<Duration> {
+        self.max_idle_time
+    }
+
+    /// Tells whether idle partitions are evicted. A partition which comes back after the
+    /// eviction counts its indices from zero again, its incidents have a new `Incident::epoch`.
+    pub fn evicts_idle_partitions(&self) -> bool {
+        self.max_idle_events.is_some() || self.max_idle_time.is_some()
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    /// When the events are reordered by `SimpleMachineMapper::with_event_time`, the watermark
+    /// of a partition is its latest timestamp minus this value.
+    pub fn allowed_lateness(&self) -> u64 {
+        self.allowed_lateness
+    }
+
+    /// Lag of the global watermark behind the latest timestamp of all partitions, so the events
+    /// of quiet partitions are not kept forever when the events are reordered. Is not used if
+    /// not set.
+    pub fn reorder_idle_timeout(&self) -> Option<u64> {
+        self.reorder_idle_timeout
+    }
+
+    pub fn late_event_policy(&self) -> LateEventPolicy {
+        self.late_event_policy
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_chunk_size: 100,
+            max_buffered_events: 1000,
+            max_buffered_partitions: 1000,
+            flush_policy: FlushPolicy::default(),
+            output_ordering: OutputOrdering::default(),
+            max_idle_events: None,
+            max_idle_time: None,
+            eviction_policy: EvictionPolicy::default(),
+            allowed_lateness: 0,
+            reorder_idle_timeout: None,
+            late_event_policy: LateEventPolicy::default(),
+        }
+    }
+}
+
+pub struct EngineConfigBuilder {
+    config: EngineConfig,
+}
+
+impl EngineConfigBuilder {
+    pub fn max_chunk_size(mut self, max_chunk_size: usize) -> Self {
+        self.config.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    pub fn max_buffered_events(mut self, max_buffered_events: usize) -> Self {
+        self.config.max_buffered_events = max_buffered_events;
+        self
+    }
+
+    pub fn max_buffered_partitions(mut self, max_buffered_partitions: usize) -> Self {
+        self.config.max_buffered_partitions = max_buffered_partitions;
+        self
+    }
+
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.config.flush_policy = flush_policy;
+        self
+    }
+
+    pub fn output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+        self.config.output_ordering = output_ordering;
+        self
+    }
+
+    pub fn max_idle_events(mut self, max_idle_events: u64) -> Self {
+        self.config.max_idle_events = Some(max_idle_events);
+        self
+    }
+
+    pub fn max_idle_time(mut self, max_idle_time: Duration) -> Self {
+        self.config.max_idle_time = Some(max_idle_time);
+        self
+    }
+
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.config.eviction_policy = eviction_policy;
+        self
+    }
+
+    pub fn allowed_lateness(mut self, allowed_lateness: u64) -> Self {
+        self.config.allowed_lateness = allowed_lateness;
+        self
+    }
+
+    pub fn reorder_idle_timeout(mut self, reorder_idle_timeout: u64) -> Self {
+        self.config.reorder_idle_timeout = Some(reorder_idle_timeout);
+        self
+    }
+
+    pub fn late_event_policy(mut self, late_event_policy: LateEventPolicy) -> Self {
+        self.config.late_event_policy = late_event_policy;
+        self
+    }
+
+    pub fn build(self) -> Result<EngineConfig, ConfigError> {
+        let config = self.config;
+        if config.max_chunk_size == 0 {
+            return Err(ConfigError::ZeroLimit("max_chunk_size"));
+        }
+        if config.max_buffered_events == 0 {
+            return Err(ConfigError::ZeroLimit("max_buffered_events"));
+        }
+        if config.max_buffered_partitions == 0 {
+            return Err(ConfigError::ZeroLimit("max_buffered_partitions"));
+        }
+        if config.max_idle_events == Some(0) {
+            return Err(ConfigError::ZeroLimit("max_idle_events"));
+        }
+        if config.max_idle_time == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroLimit("max_idle_time"));
+        }
+        if config.reorder_idle_timeout == Some(0) {
+            return Err(ConfigError::ZeroLimit("reorder_idle_timeout"));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The limit with the given name must be greater than zero.
+    ZeroLimit(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroLimit(name) => write!(f, "{} must be greater than zero", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_limits() {
+        assert_eq!(
+            EngineConfig::builder().max_chunk_size(0).build(),
+            Err(ConfigError::ZeroLimit("max_chunk_size"))
+        );
+        let config = EngineConfig::builder()
+            .max_chunk_size(10)
+            .flush_policy(FlushPolicy::Oldest)
+            .build()
+            .unwrap();
+        assert_eq!(config.max_chunk_size(), 10);
+        assert_eq!(config.max_buffered_events(), 1000);
+        assert_eq!(config.flush_policy(), FlushPolicy::Oldest);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::{QueryIncident, SimpleMachineMapper};
+use crate::tsp::runtime::{OutputBuffer, PartitionState, QueryCore};
+
+/// Push-based counterpart of `TSPIter` for the events which arrive one by one, e.g. from
+/// callbacks. Computed incidents are collected by `drain_incidents`. The events are reordered
+/// by timestamps first if `SimpleMachineMapper::with_event_time` is set, the same as by the
+/// other run methods.
+pub struct Engine<'a, Proj, Pat, Part>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    mapper: &'a SimpleMachineMapper<Proj, Pat, Part>,
+    reorder: ReorderBuffer<Part::T, u64, Proj::Event>,
+    buffer: PartitionBuffer<Part::T, Proj::Event>,
+    core: QueryCore<'a, Proj, Pat, Part::T>,
+    output: OutputBuffer<Part::T, QueryIncident<Proj, Pat, Part>>,
+}
+
+impl<'a, Proj, Pat, Part> Engine<'a, Proj, Pat, Part>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    pub fn new(mapper: &'a SimpleMachineMapper<Proj, Pat, Part>) -> Self {
+        Engine {
+            mapper,
+            reorder: ReorderBuffer::default(),
+            buffer: PartitionBuffer::new(&mapper.config),
+            core: QueryCore::new(mapper),
+            output: OutputBuffer::new(mapper.config.output_ordering()),
+        }
+    }
+
+    pub fn push(&mut self, event: Proj::Event) {
+        let key = self.mapper.partitioner.partition_key(&event);
+        let order = match self.mapper.event_order() {
+            Some(order) => order,
+            None => return self.push_ordered(key, event),
+        };
+        let mut ready = VecDeque::new();
+        order.push(&mut self.reorder, key, event, &mut ready);
+        ready
+            .into_iter()
+            .for_each(|(key, event)| self.push_ordered(key, event));
+    }
+
+    pub fn push_batch(&mut self, events: &[Proj::Event])
+    where
+        Proj::Event: Clone,
+    {
+        events.iter().for_each(|event| self.push(event.clone()))
+    }
+
+    /// Processes all buffered events without waiting for their chunks to be filled and evicts
+    /// the partitions which became idle since the last event.
+    pub fn flush(&mut self) {
+        while let Some(chunk) = self.buffer.flush() {
+            self.process(chunk);
+        }
+        self.evict_idle();
+    }
+
+    /// Returns the computed incidents which are ready in the configured `OutputOrdering`.
+    pub fn drain_incidents(&mut self) -> Vec<ProjectionResult<QueryIncident<Proj, Pat, Part>>> {
+        let buffered = self.buffer.first_buffered_position();
+        std::iter::from_fn(|| self.output.pop(buffered, false)).collect()
+    }
+
+    /// Flushes the buffered events and returns all incidents left, no more events are expected.
+    pub fn finish(mut self) -> Vec<ProjectionResult<QueryIncident<Proj, Pat, Part>>> {
+        self.reorder
+            .finish()
+            .into_iter()
+            .for_each(|(key, event)| self.push_ordered(key, event));
+        self.flush();
+        std::iter::from_fn(|| self.output.pop(None, true)).collect()
+    }
+
+    fn push_ordered(&mut self, key: Part::T, event: Proj::Event) {
+        if let Some(chunk) = self.buffer.push(key, event) {
+            self.process(chunk);
+        }
+        self.evict_idle();
+    }
+
+    fn process(&mut self, chunk: Chunk<Part::T, Proj::Event>) {
+        self.output.dispatch(&chunk.key, chunk.positions[0]);
+        let output = self.core.process(&chunk);
+        self.output.push(output);
+    }
+
+    fn evict_idle(&mut self) {
+        while let Some((key, chunk)) = self.buffer.evict_idle() {
+            if let Some(chunk) = chunk {
+                self.process(chunk);
+            }
+            self.mapper.evicted(&key);
+            let output = self.core.evict(key);
+            self.output.push(output);
+        }
+    }
+
+    /// Restores the engine serialized before, e.g. by the previous instance of the service.
+    /// `mapper` must have the same rule, projection and config. The state has maps with
+    /// non-string keys, so the format must support them, e.g. bincode but not JSON. All
+    /// built-in states are serializable except the ones with erased types:
+    /// `RecordProjectionState` of `RecordProjection` and `BoxedSymbolState` of `BoxedSymbol`
+    /// symbols of `SequencePattern`, so engines using them can't be serialized.
+    pub fn restore<'de, D>(
+        mapper: &'a SimpleMachineMapper<Proj, Pat, Part>,
+        deserializer: D,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        Part::T: Deserialize<'de>,
+        Proj::Event: Deserialize<'de>,
+        Pat::State: Deserialize<'de>,
+        Pat::T: Deserialize<'de>,
+        Proj::State: Deserialize<'de>,
+        Proj::T: Deserialize<'de>,
+    {
+        let state = EngineState::deserialize(deserializer)?;
+        Ok(Engine {
+            mapper,
+            reorder: state.reorder,
+            buffer: state.buffer,
+            core: QueryCore::with_partitions(mapper, state.partitions),
+            output: state.output,
+        })
+    }
+}
+
+// Checkpoint of the engine: buffered events, states of all partitions and incidents which
+// were not drained yet.
+#[derive(Serialize)]
+struct EngineStateRef<'e, K, E, S, P, R, I> {
+    reorder: &'e ReorderBuffer<K, u64, E>,
+    buffer: &'e PartitionBuffer<K, E>,
+    partitions: &'e HashMap<K, PartitionState<S, P, R>>,
+    output: &'e OutputBuffer<K, I>,
+}
+
+#[derive(Deserialize)]
+#[serde(
+    bound(deserialize = "K: Deserialize<'de> + Eq + Hash, E: Deserialize<'de>, \
+    S: Deserialize<'de>, P: Deserialize<'de>, R: Deserialize<'de>, I: Deserialize<'de>")
+)]
+struct EngineState<K, E, S, P, R, I> {
+    reorder: ReorderBuffer<K, u64, E>,
+    buffer: PartitionBuffer<K, E>,
+    partitions: HashMap<K, PartitionState<S, P, R>>,
+    output: OutputBuffer<K, I>,
+}
+
+impl<Proj, Pat, Part> Serialize for Engine<'_, Proj, Pat, Part>
+where
+    Proj: Projection,
+    Proj::Event: Serialize,
+    Proj::State: Serialize,
+    Proj::T: Serialize,
+    Pat: Pattern<Event = Proj::Event>,
+    Pat::State: Serialize,
+    Pat::T: Serialize,
+    Part: Partitioner<Event = Proj::Event>,
+    Part::T: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EngineStateRef {
+            reorder: &self.reorder,
+            buffer: &self.buffer,
+            partitions: self.core.partitions(),
+            output: &self.output,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<Proj, Pat, Part> SimpleMachineMapper<Proj, Pat, Part>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    pub fn engine(&self) -> Engine<'_, Proj, Pat, Part> {
+        Engine::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::config::EngineConfig;
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+    use crate::tsp::query::Incident;
+    use bincode::Options;
+
+    struct TE(u64, u64);
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct OwnedTE(u64);
+
+    #[test]
+    fn restores_checkpoint() {
+        let events: Vec<OwnedTE> = (0..30).map(OwnedTE).collect();
+        let config = EngineConfig::builder().max_chunk_size(4).build().unwrap();
+        let mapper = SimpleMachineMapper::new(
+            SumProjection::new(|e: &OwnedTE| e.0),
+            FunctionPattern::new(|e: &OwnedTE| e.0 / 7),
+            NoPartitioner::new(),
+        )
+        .with_config(config);
+
+        let mut engine = mapper.engine();
+        engine.push_batch(&events[..15]);
+        let checkpoint = bincode::options().serialize(&engine).unwrap();
+        engine.push_batch(&events[15..]);
+        let expected = engine.finish();
+
+        let mut restored = Engine::restore(
+            &mapper,
+            &mut bincode::Deserializer::from_slice(&checkpoint, bincode::options()),
+        )
+        .unwrap();
+        restored.push_batch(&events[15..]);
+        assert_eq!(restored.finish(), expected);
+    }
+
+    #[test]
+    fn same_output_as_iterator() {
+        let events: Vec<TE> = (0..100).map(|i| TE(i % 3, i)).collect();
+        let events: Vec<&TE> = events.iter().collect();
+        let config = EngineConfig::builder().max_chunk_size(4).build().unwrap();
+        let mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1.is_multiple_of(5)),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+
+        let mut engine = mapper.engine();
+        let mut pushed = vec![];
+        engine.push(events[0]);
+        engine.push_batch(&events[1..50]);
+        pushed.extend(engine.drain_incidents());
+        engine.push_batch(&events[50..]);
+        engine.flush();
+        pushed.extend(engine.drain_incidents());
+        assert!(engine.finish().is_empty());
+
+        let expected: Vec<_> = mapper.run(events.into_iter()).collect();
+        assert_eq!(pushed, expected);
+    }
+
+    #[test]
+    fn reorders_events() {
+        let events: Vec<TE> = (0..60).map(|i| TE(i % 3, i)).collect();
+        let config = EngineConfig::builder()
+            .max_chunk_size(4)
+            .allowed_lateness(6)
+            .reorder_idle_timeout(20)
+            .build()
+            .unwrap();
+        let mapper = |config| {
+            SimpleMachineMapper::new(
+                LastProjection::new(|e: &&TE| e.1),
+                FunctionPattern::new(|e: &&TE| e.1.is_multiple_of(5)),
+                FunctionPartitioner::new(|e: &TE| e.0),
+            )
+            .with_config(config)
+        };
+        // the epochs are the positions of the first events of the partitions in the input of
+        // the query, so they depend on the order of the events
+        let without_epoch = |incidents: Vec<ProjectionResult<Incident<u64, bool, u64>>>| {
+            incidents
+                .into_iter()
+                .map(|incident| {
+                    let incident = incident.unwrap();
+                    (
+                        incident.key,
+                        incident.start_idx,
+                        incident.end_idx,
+                        incident.projection,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = without_epoch(mapper(config).run(events.iter()).collect());
+
+        // every partition gets its pairs of events swapped
+        let mut shuffled: Vec<&TE> = events.iter().collect();
+        shuffled.chunks_mut(6).for_each(|c| c.rotate_left(3));
+        let mapper = mapper(config).with_event_time(|e: &&TE| e.1);
+        let mut engine = mapper.engine();
+        engine.push_batch(&shuffled);
+        assert_eq!(without_epoch(engine.finish()), expected);
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::config::ConfigError;
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::SimpleMachineMapper;
+use crate::tsp::runtime::{OutputBuffer, QueryCore};
+
+/// Stage of the incident reported by `LifecycleIter`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Lifecycle<P> {
+    /// The success interval has started, the next events may continue it.
+    Opened,
+    /// The incident is still open, the projection of its events so far.
+    Updated(P),
+    /// The incident is finished, the projection of all its events.
+    Closed(P),
+}
+
+/// Output of `LifecycleIter`: the stage of the incident `[start_idx, end_idx]` of the partition
+/// `key` where the rule succeeded with `value`. `end_idx` is the last event known so far. The
+/// indices and `epoch` have the same meaning as in `Incident`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LifecycleEvent<K, R, P> {
+    pub key: K,
+    pub epoch: Position,
+    pub start_idx: Idx,
+    pub end_idx: Idx,
+    pub value: R,
+    pub stage: Lifecycle<P>,
+}
+
+/// `LifecycleEvent` returned by the query with the given projection, rule and partitioner.
+pub type QueryLifecycleEvent<Proj, Pat, Part> =
+    LifecycleEvent<<Part as Partitioner>::T, <Pat as Pattern>::T, <Proj as Projection>::T>;
+
+impl<Proj, Pat, Part> SimpleMachineMapper<Proj, Pat, Part>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Pat::T: PartialEq,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    /// Same as `run`, but the success intervals are reported as soon as the rule returns their
+    /// first events and are closed when the rule returns a different result. Open incidents get
+    /// `Lifecycle::Updated` every `update_every` events, so the projection must implement
+    /// `Projection::peek`. Other intervals are not reported. Events are returned in the
+    /// configured `OutputOrdering`, all events of an incident share the position of its start.
+    /// Open incidents of evicted partitions are closed regardless of `EvictionPolicy`.
+    pub fn run_lifecycle<J>(
+        &self,
+        events_iter: J,
+        update_every: Option<Idx>,
+    ) -> Result<LifecycleIter<'_, Proj, Pat, Part, J>, ConfigError>
+    where
+        J: Iterator<Item = Proj::Event>,
+    {
+        if update_every == Some(0) {
+            return Err(ConfigError::ZeroLimit("update_every"));
+        }
+        Ok(LifecycleIter {
+            mapper: self,
+            partition_iterator: events_iter
+                .partition_by(&self.partitioner, &self.config)
+                .with_order(self.event_order()),
+            core: QueryCore::new(self),
+            output: OutputBuffer::new(self.config.output_ordering()),
+            update_every,
+            finished: false,
+        })
+    }
+}
+
+pub struct LifecycleIter<'a, Proj, Pat, Part, J>
+where
+    J: Iterator<Item = Proj::Event>,
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    mapper: &'a SimpleMachineMapper<Proj, Pat, Part>,
+    partition_iterator: PartitionIterator<'a, J, Part>,
+    core: QueryCore<'a, Proj, Pat, Part::T>,
+    output: OutputBuffer<Part::T, QueryLifecycleEvent<Proj, Pat, Part>>,
+    update_every: Option<Idx>,
+    finished: bool,
+}
+
+impl<Proj, Pat, Part, J> Iterator for LifecycleIter<'_, Proj, Pat, Part, J>
+where
+    J: Iterator<Item = Proj::Event>,
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Pat::T: PartialEq,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    type Item = ProjectionResult<QueryLifecycleEvent<Proj, Pat, Part>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let buffered = self.partition_iterator.first_buffered_position();
+            if let Some(event) = self.output.pop(buffered, self.finished) {
+                return Some(event);
+            }
+            if self.finished {
+                return None;
+            }
+
+            if let Some((key, chunk)) = self.partition_iterator.evict_idle() {
+                if let Some(chunk) = chunk {
+                    self.output.dispatch(&chunk.key, chunk.positions[0]);
+                    let output = self.core.process_lifecycle(&chunk, self.update_every);
+                    self.output.push(output);
+                }
+                self.mapper.evicted(&key);
+                let output = self.core.evict_lifecycle(key);
+                self.output.push(output);
+                continue;
+            }
+
+            match self.partition_iterator.next() {
+                Some(chunk) => {
+                    self.output.dispatch(&chunk.key, chunk.positions[0]);
+                    let output = self.core.process_lifecycle(&chunk, self.update_every);
+                    self.output.push(output);
+                }
+                None => {
+                    // the input is over, so the open incidents are closed in the order of
+                    // their partitions
+                    for output in self.core.close_lifecycle() {
+                        self.output.push(output);
+                    }
+                    self.finished = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::config::{EngineConfig, OutputOrdering};
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+
+    #[test]
+    fn reports_lifecycle() {
+        let values = [1, 3, 4, 5, 6, 1, 3];
+        let config = EngineConfig::builder().max_chunk_size(2).build().unwrap();
+        let mapper = SimpleMachineMapper::new(
+            SumProjection::new(|e: &&u64| **e),
+            AssertPattern::new(FunctionPattern::new(|e: &&u64| **e > 2)),
+            NoPartitioner::new(),
+        )
+        .with_config(config);
+        let events: Vec<_> = mapper
+            .run_lifecycle(values.iter(), Some(2))
+            .unwrap()
+            .map(|event| {
+                let event = event.unwrap();
+                (event.start_idx, event.end_idx, event.stage)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, 1, Lifecycle::Opened),
+                (1, 3, Lifecycle::Updated(12)),
+                (1, 4, Lifecycle::Closed(18)),
+                (6, 6, Lifecycle::Opened),
+                (6, 6, Lifecycle::Closed(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_and_evicts_incidents() {
+        // (partition, value), partition 0 is idle after its third event
+        let events = [
+            (0, 3),
+            (1, 1),
+            (0, 4),
+            (1, 3),
+            (0, 5),
+            (1, 4),
+            (1, 5),
+            (1, 1),
+        ];
+        let config = EngineConfig::builder()
+            .max_chunk_size(1)
+            .max_idle_events(2)
+            .output_ordering(OutputOrdering::ByStart)
+            .build()
+            .unwrap();
+        let mapper = SimpleMachineMapper::new(
+            SumProjection::new(|e: &&(u64, u64)| e.1),
+            AssertPattern::new(FunctionPattern::new(|e: &&(u64, u64)| e.1 > 2)),
+            FunctionPartitioner::new(|e: &(u64, u64)| e.0),
+        )
+        .with_config(config);
+        assert_eq!(
+            mapper.run_lifecycle(events.iter(), Some(0)).err(),
+            Some(ConfigError::ZeroLimit("update_every"))
+        );
+
+        let events: Vec<_> = mapper
+            .run_lifecycle(events.iter(), None)
+            .unwrap()
+            .map(|event| {
+                let event = event.unwrap();
+                (event.key, event.start_idx, event.end_idx, event.stage)
+            })
+            .collect();
+        // the incident of partition 0 is closed by the eviction and both incidents are ordered
+        // by their starts
+        assert_eq!(
+            events,
+            vec![
+                (0, 0, 0, Lifecycle::Opened),
+                (0, 0, 2, Lifecycle::Closed(12)),
+                (1, 1, 1, Lifecycle::Opened),
+                (1, 1, 3, Lifecycle::Closed(12)),
+            ]
+        );
+    }
+}
@@ -1,4 +1,12 @@
+pub mod config;
+pub mod engine;
+pub mod lifecycle;
+pub mod multi;
+pub mod parallel;
 pub mod partitioners;
 pub mod patterns;
 pub mod projections;
 pub mod query;
+mod runtime;
+#[cfg(feature = "async")]
+pub mod stream;
@@ -0,0 +1,503 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::tsp::config::EngineConfig;
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::{EventTime, EvictHook, Incident, LateEventHook, UnknownPolicy};
+use crate::tsp::runtime::{
+    ignore_value, ChunkOutput, ExtractValue, OutputBuffer, QueryCore, QuerySpec,
+};
+
+/// Index of the rule in `MultiQuery`, rules are numbered in the order they are added.
+pub type RuleId = usize;
+
+/// Output of `MultiQuery`, the incident of the rule with the given id.
+pub type RuleIncident<K, R, P> = (RuleId, ProjectionResult<Incident<K, R, P>>);
+
+type BoxedRule<'r, Part, R, P> =
+    Box<dyn Rule<<Part as Partitioner>::T, <Part as Partitioner>::Event, R, P> + 'r>;
+
+/// Runs many rules over one pass of the input. Events are partitioned once and every chunk is
+/// passed to all rules, every rule has its own projection. Incidents are tagged by the `RuleId`
+/// of their rule, so all rules must have the same pattern value `R` and projection `P` types,
+/// `RecordProjection` may be used for different projections.
+pub struct MultiQuery<'r, Part, R, P>
+where
+    Part: Partitioner,
+    R: Clone,
+{
+    partitioner: Part,
+    config: EngineConfig,
+    rules: Vec<BoxedRule<'r, Part, R, P>>,
+    shared: Vec<Rc<dyn SharedCache + 'r>>,
+    on_evict: Option<EvictHook<Part::T>>,
+    event_time: Option<EventTime<Part::Event>>,
+    on_late_event: Option<LateEventHook<Part::T, Part::Event>>,
+}
+
+impl<'r, Part, R, P> MultiQuery<'r, Part, R, P>
+where
+    Part: Partitioner,
+    R: Clone,
+{
+    pub fn new(partitioner: Part) -> Self {
+        MultiQuery {
+            partitioner,
+            config: EngineConfig::default(),
+            rules: Vec::new(),
+            shared: Vec::new(),
+            on_evict: None,
+            event_time: None,
+            on_late_event: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Same as `SimpleMachineMapper::with_on_evict`, is called once for all rules.
+    pub fn with_on_evict<F>(mut self, on_evict: F) -> Self
+    where
+        F: Fn(&Part::T) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Box::new(on_evict));
+        self
+    }
+
+    /// Same as `SimpleMachineMapper::with_event_time`, the events are reordered once for all
+    /// rules.
+    pub fn with_event_time<F>(mut self, event_time: F) -> Self
+    where
+        F: Fn(&Part::Event) -> u64 + Send + Sync + 'static,
+    {
+        self.event_time = Some(Box::new(event_time));
+        self
+    }
+
+    /// Same as `SimpleMachineMapper::with_on_late_event`.
+    pub fn with_on_late_event<F>(mut self, on_late_event: F) -> Self
+    where
+        F: Fn(&Part::T, &Part::Event) + Send + Sync + 'static,
+    {
+        self.on_late_event = Some(Box::new(on_late_event));
+        self
+    }
+
+    pub fn add_rule<Proj, Pat>(&mut self, projection: Proj, rule: Pat) -> RuleId
+    where
+        Proj: Projection<Event = Part::Event, T = P> + 'r,
+        Pat: Pattern<Event = Part::Event, T = R> + 'r,
+    {
+        self.add_rule_with_policy(projection, rule, UnknownPolicy::default())
+    }
+
+    pub fn add_rule_with_policy<Proj, Pat>(
+        &mut self,
+        projection: Proj,
+        rule: Pat,
+        unknown_policy: UnknownPolicy,
+    ) -> RuleId
+    where
+        Proj: Projection<Event = Part::Event, T = P> + 'r,
+        Pat: Pattern<Event = Part::Event, T = R> + 'r,
+    {
+        self.push_rule(projection, ignore_value, rule, unknown_policy)
+    }
+
+    /// Same as `add_rule_with_policy`, but the value of `PatternResult::Success` is passed to
+    /// the projection, see `SimpleMachineMapper::with_pattern_values`.
+    pub fn add_value_rule<Proj, Pat>(
+        &mut self,
+        projection: Proj,
+        rule: Pat,
+        unknown_policy: UnknownPolicy,
+    ) -> RuleId
+    where
+        Proj: ValueProjection<R, Event = Part::Event, T = P> + 'r,
+        Pat: Pattern<Event = Part::Event, T = R> + 'r,
+    {
+        self.push_rule(
+            projection,
+            <Proj as ValueProjection<R>>::extract_with_value,
+            rule,
+            unknown_policy,
+        )
+    }
+
+    fn push_rule<Proj, Pat>(
+        &mut self,
+        projection: Proj,
+        extract_value: ExtractValue<Proj, R>,
+        rule: Pat,
+        unknown_policy: UnknownPolicy,
+    ) -> RuleId
+    where
+        Proj: Projection<Event = Part::Event, T = P> + 'r,
+        Pat: Pattern<Event = Part::Event, T = R> + 'r,
+    {
+        self.rules.push(Box::new(RuleDef {
+            projection,
+            extract_value,
+            rule,
+            unknown_policy,
+        }));
+        self.rules.len() - 1
+    }
+
+    /// Wraps the sub-pattern used by several rules, so it is applied once for every chunk.
+    pub fn share<Pat>(&mut self, pattern: Pat) -> SharedPattern<Pat>
+    where
+        Pat: Pattern<State = NoState> + 'r,
+    {
+        let slot = Rc::new(SharedSlot {
+            pattern,
+            results: RefCell::new(SharedResults::Off),
+        });
+        self.shared.push(slot.clone());
+        SharedPattern { slot }
+    }
+
+    pub fn run<J>(&self, events_iter: J) -> MultiIter<'_, Part, R, P, J>
+    where
+        J: Iterator<Item = Part::Event>,
+    {
+        MultiIter {
+            partition_iterator: events_iter
+                .partition_by(&self.partitioner, &self.config)
+                .with_order(self.event_time.as_ref().map(|event_time| EventOrder {
+                    event_time,
+                    on_late_event: self.on_late_event.as_ref(),
+                    config: &self.config,
+                })),
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| RuleOutput {
+                    runtime: rule.runtime(&self.config),
+                    output: OutputBuffer::new(self.config.output_ordering()),
+                })
+                .collect(),
+            shared: &self.shared,
+            on_evict: self.on_evict.as_ref(),
+            ready: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+/// Pattern shared by the rules of `MultiQuery`, is created by `MultiQuery::share`. The first
+/// rule which applies it to a chunk computes the results and the other rules reuse them, so the
+/// pattern must be stateless. The results are reused only while `MultiQuery` processes a chunk,
+/// anywhere else (e.g. in `SimpleMachineMapper`) the pattern is applied every time.
+pub struct SharedPattern<Pat: Pattern> {
+    slot: Rc<SharedSlot<Pat>>,
+}
+
+impl<Pat: Pattern> Clone for SharedPattern<Pat> {
+    fn clone(&self) -> Self {
+        SharedPattern {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+struct SharedSlot<Pat: Pattern> {
+    pattern: Pat,
+    results: RefCell<SharedResults<Pat::T>>,
+}
+
+enum SharedResults<T: Clone> {
+    // no chunk is processed by `MultiIter`, the results are not cached
+    Off,
+    // the chunk is processed, but no rule has applied the pattern yet
+    Empty,
+    Computed(Vec<IdxValue<T>>),
+}
+
+trait SharedCache {
+    /// Is called before `MultiIter` passes the chunk to the rules.
+    fn enable(&self);
+    /// Is called after all rules have processed the chunk.
+    fn disable(&self);
+}
+
+impl<Pat: Pattern> SharedCache for SharedSlot<Pat> {
+    fn enable(&self) {
+        self.results.replace(SharedResults::Empty);
+    }
+
+    fn disable(&self) {
+        self.results.replace(SharedResults::Off);
+    }
+}
+
+impl<Pat: Pattern<State = NoState>> Pattern for SharedPattern<Pat> {
+    type State = Pat::State;
+    type Event = Pat::Event;
+    type T = Pat::T;
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        state: &mut Self::State,
+    ) {
+        let mut results = self.slot.results.borrow_mut();
+        match &*results {
+            SharedResults::Off => self.slot.pattern.apply(start_idx, event, queue, state),
+            SharedResults::Empty => {
+                let mut computed = PQueue::default();
+                self.slot
+                    .pattern
+                    .apply(start_idx, event, &mut computed, state);
+                let computed: Vec<_> = std::iter::from_fn(|| computed.dequeue_option()).collect();
+                queue.enqueue(computed.iter().cloned());
+                *results = SharedResults::Computed(computed);
+            }
+            SharedResults::Computed(computed) => {
+                queue.enqueue(computed.iter().cloned());
+            }
+        }
+    }
+
+    type W = Pat::W;
+
+    fn width(&self) -> Self::W {
+        self.slot.pattern.width()
+    }
+}
+
+struct RuleDef<Proj: Projection, Pat: Pattern> {
+    projection: Proj,
+    extract_value: ExtractValue<Proj, Pat::T>,
+    rule: Pat,
+    unknown_policy: UnknownPolicy,
+}
+
+// Type erased rule of `MultiQuery`.
+trait Rule<K, E, R: Clone, P> {
+    fn runtime<'a>(&'a self, config: &'a EngineConfig) -> Box<dyn RuleRuntime<K, E, R, P> + 'a>
+    where
+        K: 'a,
+        E: 'a;
+}
+
+impl<K, Proj, Pat> Rule<K, Proj::Event, Pat::T, Proj::T> for RuleDef<Proj, Pat>
+where
+    K: Clone + Eq + Hash,
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+{
+    fn runtime<'a>(
+        &'a self,
+        config: &'a EngineConfig,
+    ) -> Box<dyn RuleRuntime<K, Proj::Event, Pat::T, Proj::T> + 'a>
+    where
+        K: 'a,
+        Proj::Event: 'a,
+    {
+        Box::new(QueryCore::new(QuerySpec {
+            projection: &self.projection,
+            extract_value: self.extract_value,
+            rule: &self.rule,
+            unknown_policy: self.unknown_policy,
+            config,
+        }))
+    }
+}
+
+trait RuleRuntime<K, E, R: Clone, P> {
+    fn process(&mut self, chunk: &Chunk<K, E>) -> ChunkOutput<K, Incident<K, R, P>>;
+
+    fn evict(&mut self, key: K) -> ChunkOutput<K, Incident<K, R, P>>;
+}
+
+impl<K, Proj, Pat> RuleRuntime<K, Proj::Event, Pat::T, Proj::T> for QueryCore<'_, Proj, Pat, K>
+where
+    K: Clone + Eq + Hash,
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+{
+    fn process(
+        &mut self,
+        chunk: &Chunk<K, Proj::Event>,
+    ) -> ChunkOutput<K, Incident<K, Pat::T, Proj::T>> {
+        QueryCore::process(self, chunk)
+    }
+
+    fn evict(&mut self, key: K) -> ChunkOutput<K, Incident<K, Pat::T, Proj::T>> {
+        QueryCore::evict(self, key)
+    }
+}
+
+struct RuleOutput<'a, K, E, R: Clone, P> {
+    runtime: Box<dyn RuleRuntime<K, E, R, P> + 'a>,
+    output: OutputBuffer<K, Incident<K, R, P>>,
+}
+
+pub struct MultiIter<'a, Part, R, P, J>
+where
+    J: Iterator<Item = Part::Event>,
+    Part: Partitioner,
+    R: Clone,
+{
+    partition_iterator: PartitionIterator<'a, J, Part>,
+    rules: Vec<RuleOutput<'a, Part::T, Part::Event, R, P>>,
+    shared: &'a [Rc<dyn SharedCache + 'a>],
+    on_evict: Option<&'a EvictHook<Part::T>>,
+    ready: VecDeque<RuleIncident<Part::T, R, P>>,
+    finished: bool,
+}
+
+impl<Part, R, P, J> MultiIter<'_, Part, R, P, J>
+where
+    J: Iterator<Item = Part::Event>,
+    Part: Partitioner,
+    R: Clone,
+{
+    fn process(&mut self, chunk: Chunk<Part::T, Part::Event>) {
+        self.shared.iter().for_each(|shared| shared.enable());
+        for rule in self.rules.iter_mut() {
+            rule.output.dispatch(&chunk.key, chunk.positions[0]);
+            let output = rule.runtime.process(&chunk);
+            rule.output.push(output);
+        }
+        self.shared.iter().for_each(|shared| shared.disable());
+    }
+}
+
+impl<Part, R, P, J> Iterator for MultiIter<'_, Part, R, P, J>
+where
+    J: Iterator<Item = Part::Event>,
+    Part: Partitioner,
+    R: Clone,
+{
+    type Item = RuleIncident<Part::T, R, P>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(incident) = self.ready.pop_front() {
+                return Some(incident);
+            }
+            if self.finished {
+                return None;
+            }
+
+            if let Some((key, chunk)) = self.partition_iterator.evict_idle() {
+                if let Some(chunk) = chunk {
+                    self.process(chunk);
+                }
+                if let Some(on_evict) = self.on_evict {
+                    on_evict(&key);
+                }
+                for rule in self.rules.iter_mut() {
+                    let output = rule.runtime.evict(key.clone());
+                    rule.output.push(output);
+                }
+            } else {
+                match self.partition_iterator.next() {
+                    Some(chunk) => self.process(chunk),
+                    None => self.finished = true,
+                }
+            }
+
+            // incidents of every rule are returned in the configured `OutputOrdering`
+            let buffered = self.partition_iterator.first_buffered_position();
+            for (id, rule) in self.rules.iter_mut().enumerate() {
+                while let Some(incident) = rule.output.pop(buffered, self.finished) {
+                    self.ready.push_back((id, incident));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+    use crate::tsp::query::SimpleMachineMapper;
+
+    struct TE(u64, u64);
+
+    #[test]
+    fn same_output_as_separate_queries() {
+        let events: Vec<TE> = (0..50).map(|i| TE(i % 3, i % 7)).collect();
+        let config = EngineConfig::builder().max_chunk_size(4).build().unwrap();
+        let calls = Cell::new(0);
+        let mut query = MultiQuery::new(FunctionPartitioner::new(|e: &TE| e.0)).with_config(config);
+        let above = query.share(FunctionPattern::new(|e: &&TE| {
+            calls.set(calls.get() + 1);
+            e.1 > 2
+        }));
+        let last = query.add_rule(LastProjection::new(|e: &&TE| e.1), above.clone());
+        let first = query.add_rule(FirstProjection::new(|e: &&TE| e.1), above);
+        let incidents: Vec<_> = query.run(events.iter()).collect();
+        // the shared pattern is applied once for both rules
+        assert_eq!(calls.get(), events.len());
+
+        let rule_incidents = |id| -> Vec<_> {
+            incidents
+                .iter()
+                .filter(|(rule, _)| *rule == id)
+                .map(|(_, incident)| incident.clone())
+                .collect()
+        };
+        let last_mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1 > 2),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+        let first_mapper = SimpleMachineMapper::new(
+            FirstProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1 > 2),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+        assert_eq!(
+            rule_incidents(last),
+            last_mapper.run(events.iter()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            rule_incidents(first),
+            first_mapper.run(events.iter()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn shared_pattern_outside_of_multi_query() {
+        let events: Vec<TE> = (0..20).map(|i| TE(i % 2, i % 5)).collect();
+        let config = EngineConfig::builder().max_chunk_size(3).build().unwrap();
+        let mut query: MultiQuery<_, bool, u64> =
+            MultiQuery::new(FunctionPartitioner::new(|e: &TE| e.0));
+        let above = query.share(FunctionPattern::new(|e: &&TE| e.1 > 2));
+
+        // every chunk gets its own results, not the ones of the first chunk
+        let shared_mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            above,
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+        let mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1 > 2),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+        assert_eq!(
+            shared_mapper.run(events.iter()).collect::<Vec<_>>(),
+            mapper.run(events.iter()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::tsp::config::ConfigError;
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::{QueryIncident, SimpleMachineMapper};
+use crate::tsp::runtime::{ChunkOutput, OutputBuffer, QueryCore};
+
+// number of chunks waiting for every worker, the reader blocks when it is reached
+const WORKER_QUEUE_SIZE: usize = 16;
+
+enum Task<K, E> {
+    Process(Chunk<K, E>),
+    // the partition is idle and its state is removed
+    Evict(K),
+}
+
+impl<Proj, Pat, Part> SimpleMachineMapper<Proj, Pat, Part>
+where
+    Proj: Projection + Sync,
+    Proj::T: Send,
+    Proj::Event: Send,
+    Pat: Pattern<Event = Proj::Event> + Sync,
+    Pat::T: Send,
+    Part: Partitioner<Event = Proj::Event> + Sync,
+    Part::T: Send,
+{
+    /// Same as `run`, but the chunks are processed by `workers` threads. Every partition is
+    /// always processed by the same worker which owns its states. Incidents are passed to `sink`
+    /// on the calling thread in the configured `OutputOrdering`, the same as `run` returns them.
+    /// Returns `ConfigError::ZeroLimit` if `workers` is zero.
+    pub fn run_parallel<J, S>(
+        &self,
+        events_iter: J,
+        workers: usize,
+        mut sink: S,
+    ) -> Result<(), ConfigError>
+    where
+        J: Iterator<Item = Proj::Event>,
+        S: FnMut(ProjectionResult<QueryIncident<Proj, Pat, Part>>),
+    {
+        if workers == 0 {
+            return Err(ConfigError::ZeroLimit("workers"));
+        }
+        thread::scope(|scope| {
+            let (outputs_sender, outputs) = mpsc::channel();
+            let senders: Vec<_> = (0..workers)
+                .map(|_| {
+                    let (sender, tasks) = mpsc::sync_channel(WORKER_QUEUE_SIZE);
+                    let outputs_sender = outputs_sender.clone();
+                    scope.spawn(move || {
+                        let mut core = QueryCore::new(self);
+                        for (seq, task) in tasks {
+                            let output = match task {
+                                Task::Process(chunk) => core.process(&chunk),
+                                Task::Evict(key) => core.evict(key),
+                            };
+                            if outputs_sender.send((seq, output)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    sender
+                })
+                .collect();
+            drop(outputs_sender);
+
+            let mut partition_iterator = events_iter
+                .partition_by(&self.partitioner, &self.config)
+                .with_order(self.event_order());
+            let mut reorder = Reorder::new(OutputBuffer::new(self.config.output_ordering()));
+            let mut dispatched = 0;
+            let mut send = |task: Task<_, _>| {
+                let key = match &task {
+                    Task::Process(chunk) => &chunk.key,
+                    Task::Evict(key) => key,
+                };
+                senders[worker_of(key, workers)]
+                    .send((dispatched, task))
+                    .expect("Worker has stopped");
+                dispatched += 1;
+            };
+            while let Some(chunk) = partition_iterator.next() {
+                reorder.output.dispatch(&chunk.key, chunk.positions[0]);
+                send(Task::Process(chunk));
+                while let Some((key, chunk)) = partition_iterator.evict_idle() {
+                    if let Some(chunk) = chunk {
+                        reorder.output.dispatch(&chunk.key, chunk.positions[0]);
+                        send(Task::Process(chunk));
+                    }
+                    self.evicted(&key);
+                    send(Task::Evict(key));
+                }
+
+                // only the outputs which are ready, workers are not waited for
+                outputs
+                    .try_iter()
+                    .for_each(|(seq, output)| reorder.push(seq, output));
+                let buffered = partition_iterator.first_buffered_position();
+                while let Some(incident) = reorder.output.pop(buffered, false) {
+                    sink(incident);
+                }
+            }
+
+            drop(senders);
+            outputs
+                .iter()
+                .for_each(|(seq, output)| reorder.push(seq, output));
+            while let Some(incident) = reorder.output.pop(None, true) {
+                sink(incident);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn worker_of<K: Hash>(key: &K, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+// Passes outputs of the workers to `OutputBuffer` in the order the chunks were dispatched.
+struct Reorder<K, I> {
+    output: OutputBuffer<K, I>,
+    pending: BTreeMap<u64, ChunkOutput<K, I>>,
+    next_seq: u64,
+}
+
+impl<K: Clone + Eq + Hash, I> Reorder<K, I> {
+    fn new(output: OutputBuffer<K, I>) -> Self {
+        Reorder {
+            output,
+            pending: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn push(&mut self, seq: u64, output: ChunkOutput<K, I>) {
+        self.pending.insert(seq, output);
+        while let Some(output) = self.pending.remove(&self.next_seq) {
+            self.output.push(output);
+            self.next_seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::config::{EngineConfig, OutputOrdering};
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+
+    struct TE(u64, u64);
+
+    #[test]
+    fn same_output_as_sequential_run() {
+        let events: Vec<TE> = (0..1000).map(|i| TE(i % 7, i)).collect();
+        for ordering in [OutputOrdering::Completion, OutputOrdering::ByStart] {
+            let config = EngineConfig::builder()
+                .max_chunk_size(10)
+                .max_buffered_events(30)
+                .output_ordering(ordering)
+                .build()
+                .unwrap();
+            let mapper = SimpleMachineMapper::new(
+                LastProjection::new(|e: &&TE| e.1),
+                FunctionPattern::new(|e: &&TE| e.1 % 3),
+                FunctionPartitioner::new(|e: &TE| e.0),
+            )
+            .with_config(config);
+
+            assert_eq!(
+                mapper.run_parallel(events.iter(), 0, |_| ()),
+                Err(ConfigError::ZeroLimit("workers"))
+            );
+
+            let sequential: Vec<_> = mapper.run(events.iter()).collect();
+            let mut parallel = vec![];
+            mapper
+                .run_parallel(events.iter(), 4, |incident| parallel.push(incident))
+                .unwrap();
+            assert_eq!(parallel, sequential);
+        }
+    }
+
+    #[test]
+    fn evicted_partitions_come_back() {
+        // every partition gets 5 events in a row and is evicted before its next ones
+        let events: Vec<TE> = (0..3000).map(|i| TE(i / 5 % 13, i)).collect();
+        let config = EngineConfig::builder()
+            .max_chunk_size(3)
+            .max_buffered_events(20)
+            .max_idle_events(30)
+            .output_ordering(OutputOrdering::ByStart)
+            .build()
+            .unwrap();
+        let positive =
+            || AssertPattern::new(FunctionPattern::new(|e: &&TE| !e.1.is_multiple_of(3)));
+        let mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            AndThenPattern::new(positive(), positive()),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+
+        let sequential: Vec<_> = mapper.run(events.iter()).map(Result::unwrap).collect();
+        let mut indices: Vec<_> = sequential
+            .iter()
+            .map(|i| (i.key, i.epoch, i.start_idx))
+            .collect();
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), sequential.len());
+
+        for _ in 0..10 {
+            let mut parallel = vec![];
+            mapper
+                .run_parallel(events.iter(), 4, |incident| {
+                    parallel.push(incident.unwrap())
+                })
+                .unwrap();
+            assert_eq!(parallel, sequential);
+        }
+    }
+}
@@ -1,6 +1,12 @@
+mod partition_buffer;
 mod partition_iterator;
 pub mod partitioner;
+mod reorder_iterator;
 
+pub(crate) use self::partition_buffer::PartitionBuffer;
+pub use self::partition_buffer::Position;
 pub use self::partition_iterator::*;
 pub use self::partitioner::NoPartitioner;
 pub use self::partitioner::Partitioner;
+pub use self::reorder_iterator::*;
+pub(crate) use self::reorder_iterator::{EventOrder, ReorderBuffer};
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::config::{EngineConfig, FlushPolicy};
+use crate::tsp::partitioners::partition_iterator::Chunk;
+
+/// Index of the event in the whole input, counted over all partitions.
+pub type Position = u64;
+
+/// Key of the evicted partition with the partial chunk of its buffered events.
+pub(crate) type Eviction<K, E> = (K, Option<Chunk<K, E>>);
+
+/// Collects events of every partition into chunks, respecting the limits of `EngineConfig`.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "K: Deserialize<'de> + Eq + Hash, E: Deserialize<'de>"))]
+pub(crate) struct PartitionBuffer<K, E> {
+    chunk_max_size: usize,
+    map: HashMap<K, Chunk<K, E>>,
+    // keys of the buffered partitions by the position of their first buffered event, so the
+    // choice of flushed partition doesn't depend on the order of `map`
+    by_position: BTreeMap<Position, K>,
+    // sizes and first positions of the buffered partitions, tracked only for
+    // `FlushPolicy::Largest`
+    by_size: BTreeSet<(usize, Position)>,
+    total_size: usize,
+    total_size_limit: usize,
+    partitions_limit: usize,
+    flush_policy: FlushPolicy,
+    next_position: Position,
+    max_idle_events: Option<u64>,
+    max_idle_time: Option<Duration>,
+    // last position of every partition and the partitions by their last position with the time
+    // of the last event, tracked only when idle partitions are evicted
+    last_seen: HashMap<K, Position>,
+    by_last_seen: BTreeMap<Position, (K, Option<SystemTime>)>,
+}
+
+impl<K: Clone + Eq + Hash, E> PartitionBuffer<K, E> {
+    pub(crate) fn new(config: &EngineConfig) -> Self {
+        PartitionBuffer {
+            chunk_max_size: config.max_chunk_size(),
+            map: HashMap::new(),
+            by_position: BTreeMap::new(),
+            by_size: BTreeSet::new(),
+            total_size: 0,
+            total_size_limit: config.max_buffered_events(),
+            partitions_limit: config.max_buffered_partitions(),
+            flush_policy: config.flush_policy(),
+            next_position: 0,
+            max_idle_events: config.max_idle_events(),
+            max_idle_time: config.max_idle_time(),
+            last_seen: HashMap::new(),
+            by_last_seen: BTreeMap::new(),
+        }
+    }
+
+    /// Position of the oldest event which is buffered and was not returned in a chunk yet.
+    pub(crate) fn first_buffered_position(&self) -> Option<Position> {
+        self.by_position.keys().next().copied()
+    }
+
+    /// Buffers the event, returns a chunk if the event fills it or one of the limits is reached.
+    pub(crate) fn push(&mut self, key: K, event: E) -> Option<Chunk<K, E>> {
+        let position = self.next_position;
+        self.next_position += 1;
+        if self.max_idle_events.is_some() || self.max_idle_time.is_some() {
+            if let Some(last) = self.last_seen.insert(key.clone(), position) {
+                self.by_last_seen.remove(&last);
+            }
+            let time = self.max_idle_time.map(|_| SystemTime::now());
+            self.by_last_seen.insert(position, (key.clone(), time));
+        }
+        let chunk = self.map.entry(key.clone()).or_insert_with(|| Chunk {
+            key: key.clone(),
+            elements: Vec::new(),
+            positions: Vec::new(),
+        });
+        if chunk.elements.is_empty() {
+            self.by_position.insert(position, key.clone());
+        }
+        if self.flush_policy == FlushPolicy::Largest {
+            let first = chunk.positions.first().copied().unwrap_or(position);
+            self.by_size.remove(&(chunk.elements.len(), first));
+            self.by_size.insert((chunk.elements.len() + 1, first));
+        }
+
+        if chunk.elements.capacity() <= self.chunk_max_size {
+            chunk.elements.reserve(self.chunk_max_size);
+        }
+        chunk.elements.push(event);
+        chunk.positions.push(position);
+        self.total_size += 1;
+
+        if chunk.elements.len() >= self.chunk_max_size {
+            return Some(self.remove(&key));
+        }
+
+        // if we overcome one of the limits, then return a partial chunk.
+        if self.total_size >= self.total_size_limit || self.map.len() > self.partitions_limit {
+            return self.flush();
+        }
+        None
+    }
+
+    /// Removes the partial chunk of the partition chosen by the flush policy.
+    pub(crate) fn flush(&mut self) -> Option<Chunk<K, E>> {
+        let position = match self.flush_policy {
+            // the oldest one of the largest partitions
+            FlushPolicy::Largest => {
+                let &(size, _) = self.by_size.iter().next_back()?;
+                self.by_size.range((size, 0)..).next()?.1
+            }
+            FlushPolicy::Oldest => *self.by_position.keys().next()?,
+        };
+        let key = self.by_position[&position].clone();
+        Some(self.remove(&key))
+    }
+
+    /// Removes the partition which is idle longer than one of the limits of `EngineConfig`,
+    /// returns its key and the partial chunk of its buffered events.
+    pub(crate) fn evict_idle(&mut self) -> Option<Eviction<K, E>> {
+        let (&position, (key, time)) = self.by_last_seen.iter().next()?;
+        let idle_events = self.next_position - 1 - position;
+        let idle_time = time.and_then(|time| SystemTime::now().duration_since(time).ok());
+        let idle = self.max_idle_events.is_some_and(|max| idle_events >= max)
+            || matches!((idle_time, self.max_idle_time), (Some(idle), Some(max)) if idle >= max);
+        if !idle {
+            return None;
+        }
+
+        let key = key.clone();
+        self.by_last_seen.remove(&position);
+        self.last_seen.remove(&key);
+        let chunk = if self.map.contains_key(&key) {
+            Some(self.remove(&key))
+        } else {
+            None
+        };
+        Some((key, chunk))
+    }
+
+    fn remove(&mut self, key: &K) -> Chunk<K, E> {
+        let chunk = self.map.remove(key).expect("Illegal state");
+        self.by_position.remove(&chunk.positions[0]);
+        self.by_size
+            .remove(&(chunk.elements.len(), chunk.positions[0]));
+        self.total_size -= chunk.elements.len();
+        chunk
+    }
+}
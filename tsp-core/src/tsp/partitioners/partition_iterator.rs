@@ -1,44 +1,47 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::config::EngineConfig;
+use crate::tsp::partitioners::partition_buffer::{Eviction, PartitionBuffer, Position};
+use crate::tsp::partitioners::reorder_iterator::{EventOrder, ReorderBuffer};
 use crate::tsp::partitioners::Partitioner;
 
 /// Contains partitioning key K and &Vec[E] with elements
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub struct Chunk<K, E> {
     pub key: K,
     pub elements: Vec<E>,
+    /// `Position` of every element.
+    pub positions: Vec<Position>,
 }
 
 pub(crate) trait PartitionIterTool: Iterator + Sized {
-    fn partition_by<P>(
+    fn partition_by<'a, P>(
         self,
-        partitioner: &P,
-        chunk_max_size: usize,
-        total_size_limit: usize,
-    ) -> PartitionIterator<Self, P>
+        partitioner: &'a P,
+        config: &EngineConfig,
+    ) -> PartitionIterator<'a, Self, P>
     where
         P: Partitioner<Event = Self::Item>;
 }
 
 impl<T: Iterator> PartitionIterTool for T {
-    fn partition_by<P>(
+    fn partition_by<'a, P>(
         self,
-        partitioner: &P,
-        chunk_max_size: usize,
-        total_size_limit: usize,
-    ) -> PartitionIterator<Self, P>
+        partitioner: &'a P,
+        config: &EngineConfig,
+    ) -> PartitionIterator<'a, Self, P>
     where
         P: Partitioner<Event = Self::Item>,
     {
-        assert!(chunk_max_size > 0);
-        assert!(total_size_limit > 0);
         PartitionIterator {
             iter: self,
             partitioner,
-            chunk_max_size,
-            map: HashMap::new(),
-            total_size: 0,
-            total_size_limit,
+            buffer: PartitionBuffer::new(config),
+            order: None,
+            reorder: ReorderBuffer::default(),
+            ready: VecDeque::new(),
         }
     }
 }
@@ -50,10 +53,36 @@ where
 {
     iter: J,
     partitioner: &'a Part,
-    chunk_max_size: usize,
-    map: std::collections::HashMap<Part::T, Vec<J::Item>>,
-    total_size: usize,
-    total_size_limit: usize,
+    buffer: PartitionBuffer<Part::T, J::Item>,
+    // the events are reordered by `order` before they are buffered, if it is set
+    order: Option<EventOrder<'a, Part::T, J::Item>>,
+    reorder: ReorderBuffer<Part::T, u64, J::Item>,
+    // events which are ready to be buffered
+    ready: VecDeque<(Part::T, J::Item)>,
+}
+
+impl<'a, J, Part> PartitionIterator<'a, J, Part>
+where
+    J: Iterator,
+    Part: Partitioner<Event = J::Item>,
+{
+    /// Reorders the events by timestamps before they are split into chunks, see
+    /// `SimpleMachineMapper::with_event_time`.
+    pub(crate) fn with_order(mut self, order: Option<EventOrder<'a, Part::T, J::Item>>) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Position of the oldest event which is buffered and was not returned in a chunk yet.
+    pub(crate) fn first_buffered_position(&self) -> Option<Position> {
+        self.buffer.first_buffered_position()
+    }
+
+    /// Removes the partition which is idle longer than one of the limits of `EngineConfig`,
+    /// returns its key and the partial chunk of its buffered events.
+    pub(crate) fn evict_idle(&mut self) -> Option<Eviction<Part::T, J::Item>> {
+        self.buffer.evict_idle()
+    }
 }
 
 impl<J, Part> Iterator for PartitionIterator<'_, J, Part>
@@ -64,42 +93,33 @@ where
     type Item = Chunk<Part::T, J::Item>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(x) = self.iter.next() {
-            let key = self.partitioner.partition_key(&x);
-            let key_clone = key.clone();
-            let chunk = self.map.entry(key).or_default();
-
-            if chunk.capacity() <= self.chunk_max_size {
-                chunk.reserve(self.chunk_max_size);
+        loop {
+            if let Some((key, x)) = self.ready.pop_front() {
+                if let Some(chunk) = self.buffer.push(key, x) {
+                    return Some(chunk);
+                }
+                continue;
             }
-            chunk.push(x);
-            self.total_size += 1;
-
-            if chunk.len() >= self.chunk_max_size {
-                let elements = self.map.remove(&key_clone).expect("Illegal state");
-                self.total_size -= elements.len();
-                return Some(Chunk {
-                    key: key_clone,
-                    elements,
-                });
-            }
-
-            // if we overcome the limit, then return first value.
-            if self.total_size >= self.total_size_limit {
-                let (key, _) = self.map.iter().next().expect("Illegal state");
-                let key = key.clone();
-                let (key, elements) = self.map.remove_entry(&key).expect("Illegal state");
-                self.total_size -= elements.len();
-                return Some(Chunk { key, elements });
+            match self.iter.next() {
+                Some(x) => {
+                    let key = self.partitioner.partition_key(&x);
+                    match &self.order {
+                        Some(order) => order.push(&mut self.reorder, key, x, &mut self.ready),
+                        None => self.ready.push_back((key, x)),
+                    }
+                }
+                None => {
+                    // the rest of the reordered events, if there are any
+                    self.ready.extend(self.reorder.finish());
+                    if self.ready.is_empty() {
+                        break;
+                    }
+                }
             }
         }
 
         // if there is not more elements in inner iterator, we start emitting all keys.
-        let (key, elements) = self
-            .map
-            .remove_entry(&self.map.keys().take(1).next()?.clone())?;
-        self.total_size -= elements.len();
-        Some(Chunk { key, elements })
+        self.buffer.flush()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -111,6 +131,15 @@ where
 mod tests {
     use super::super::partitioner::*;
     use super::*;
+    use crate::tsp::config::FlushPolicy;
+
+    fn config(chunk_max_size: usize, total_size_limit: usize) -> EngineConfig {
+        EngineConfig::builder()
+            .max_chunk_size(chunk_max_size)
+            .max_buffered_events(total_size_limit)
+            .build()
+            .unwrap()
+    }
 
     #[derive(Debug, PartialEq)]
     struct TestEvent {
@@ -133,7 +162,7 @@ mod tests {
         assert_eq!(
             empty
                 .iter()
-                .partition_by(&NoPartitioner::<&TestEvent>::new(), 1, 1)
+                .partition_by(&NoPartitioner::<&TestEvent>::new(), &config(1, 1))
                 .into_iter()
                 .next(),
             None
@@ -152,12 +181,16 @@ mod tests {
         ];
 
         let partitioner = NoPartitioner::new();
-        let mut iterator = input.iter().partition_by(&partitioner, 2, 100).into_iter();
+        let mut iterator = input
+            .iter()
+            .partition_by(&partitioner, &config(2, 100))
+            .into_iter();
         assert_eq!(
             iterator.next(),
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 1), &TestEvent::new(0, 2)],
+                positions: vec![0, 1],
             })
         );
         assert_eq!(
@@ -165,6 +198,7 @@ mod tests {
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 3), &TestEvent::new(0, 4)],
+                positions: vec![2, 3],
             })
         );
         assert_eq!(
@@ -172,6 +206,7 @@ mod tests {
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 5), &TestEvent::new(0, 6)],
+                positions: vec![4, 5],
             })
         );
         assert_eq!(iterator.next(), None);
@@ -184,13 +219,14 @@ mod tests {
         let partitioner = NoPartitioner::new();
         let mut iterator = input
             .iter()
-            .partition_by(&partitioner, 100, 100)
+            .partition_by(&partitioner, &config(100, 100))
             .into_iter();
         assert_eq!(
             iterator.next(),
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 1)],
+                positions: vec![0],
             })
         );
         assert_eq!(iterator.next(), None);
@@ -208,12 +244,16 @@ mod tests {
         ];
 
         let partitioner = NoPartitioner::new();
-        let mut iterator = input.iter().partition_by(&partitioner, 100, 2).into_iter();
+        let mut iterator = input
+            .iter()
+            .partition_by(&partitioner, &config(100, 2))
+            .into_iter();
         assert_eq!(
             iterator.next(),
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 1), &TestEvent::new(0, 2)],
+                positions: vec![0, 1],
             })
         );
         assert_eq!(
@@ -221,6 +261,7 @@ mod tests {
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 3), &TestEvent::new(0, 4)],
+                positions: vec![2, 3],
             })
         );
         assert_eq!(
@@ -228,6 +269,7 @@ mod tests {
             Some(Chunk {
                 key: (),
                 elements: vec![&TestEvent::new(0, 5), &TestEvent::new(0, 6)],
+                positions: vec![4, 5],
             })
         );
         assert_eq!(iterator.next(), None);
@@ -248,12 +290,16 @@ mod tests {
         }
 
         let partitioner = FunctionPartitioner::new(partition_fn);
-        let mut iterator = input.iter().partition_by(&partitioner, 2, 100).into_iter();
+        let mut iterator = input
+            .iter()
+            .partition_by(&partitioner, &config(2, 100))
+            .into_iter();
         assert_eq!(
             iterator.next(),
             Some(Chunk {
                 key: 0,
                 elements: vec![&TestEvent::new(0, 1), &TestEvent::new(0, 3)],
+                positions: vec![0, 2],
             })
         );
         assert_eq!(
@@ -261,6 +307,7 @@ mod tests {
             Some(Chunk {
                 key: 1,
                 elements: vec![&TestEvent::new(1, 2), &TestEvent::new(1, 4)],
+                positions: vec![1, 3],
             })
         );
         let chunk = iterator.next().expect("");
@@ -270,6 +317,73 @@ mod tests {
         assert_eq!(iterator.next(), None);
     }
 
+    #[test]
+    fn flushes_oldest_partition_over_partitions_limit() {
+        let input = [
+            TestEvent::new(0, 1),
+            TestEvent::new(1, 2),
+            TestEvent::new(1, 3),
+            TestEvent::new(2, 4),
+        ];
+        fn partition_fn(t: &TestEvent) -> usize {
+            t.partition_key
+        }
+
+        let partitioner = FunctionPartitioner::new(partition_fn);
+        let config = EngineConfig::builder()
+            .max_buffered_partitions(2)
+            .flush_policy(FlushPolicy::Oldest)
+            .build()
+            .unwrap();
+        let mut iterator = input.iter().partition_by(&partitioner, &config);
+        assert_eq!(
+            iterator.next(),
+            Some(Chunk {
+                key: 0,
+                elements: vec![&TestEvent::new(0, 1)],
+                positions: vec![0],
+            })
+        );
+    }
+
+    #[test]
+    fn flushes_largest_partition_over_partitions_limit() {
+        let input = [
+            TestEvent::new(0, 1),
+            TestEvent::new(1, 2),
+            TestEvent::new(1, 3),
+            TestEvent::new(2, 4),
+            TestEvent::new(3, 5),
+        ];
+        fn partition_fn(t: &TestEvent) -> usize {
+            t.partition_key
+        }
+
+        let partitioner = FunctionPartitioner::new(partition_fn);
+        let config = EngineConfig::builder()
+            .max_buffered_partitions(2)
+            .build()
+            .unwrap();
+        let mut iterator = input.iter().partition_by(&partitioner, &config);
+        assert_eq!(
+            iterator.next(),
+            Some(Chunk {
+                key: 1,
+                elements: vec![&TestEvent::new(1, 2), &TestEvent::new(1, 3)],
+                positions: vec![1, 2],
+            })
+        );
+        // all partitions have the same size, the oldest one is flushed
+        assert_eq!(
+            iterator.next(),
+            Some(Chunk {
+                key: 0,
+                elements: vec![&TestEvent::new(0, 1)],
+                positions: vec![0],
+            })
+        );
+    }
+
     #[test]
     fn partition_using_function_total_size_limited() {
         let input = vec![
@@ -285,7 +399,10 @@ mod tests {
         }
 
         let partitioner = FunctionPartitioner::new(partition_fn);
-        let mut iterator = input.iter().partition_by(&partitioner, 2, 1).into_iter();
+        let mut iterator = input
+            .iter()
+            .partition_by(&partitioner, &config(2, 1))
+            .into_iter();
         assert!(iterator.next().is_some());
         assert!(iterator.next().is_some());
         assert!(iterator.next().is_some());
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::ops::Add;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::config::{EngineConfig, LateEventPolicy};
+use crate::tsp::partitioners::Partitioner;
+use crate::tsp::query::{EventTime, LateEventHook};
+
+/// What happens with the event which comes when the watermark of its partition has already
+/// passed its timestamp.
+pub enum LatePolicy<'s, E> {
+    Drop,
+    /// The event is passed to the function instead of the query.
+    SideOutput(Box<dyn FnMut(E) + 's>),
+    /// The event is passed on immediately out of the timestamp order, so the rule gets it as the
+    /// next event of its partition.
+    Reprocess,
+}
+
+pub trait ReorderIterTool: Iterator + Sized {
+    /// Reorders the events of every partition by timestamps extracted by `timestamp`. The
+    /// watermark of a partition is its latest timestamp minus `allowed_lateness`, events are kept
+    /// until the watermark passes them, events behind the watermark are handled by `late_policy`.
+    /// Events of the partitions which become quiet are released by
+    /// `ReorderIterator::with_idle_timeout`.
+    fn reorder_by<'a, P, F, Ts, L>(
+        self,
+        partitioner: &'a P,
+        timestamp: F,
+        allowed_lateness: L,
+        late_policy: LatePolicy<'a, Self::Item>,
+    ) -> ReorderIterator<'a, Self, P, F, Ts, L>
+    where
+        P: Partitioner<Event = Self::Item>,
+        F: Fn(&Self::Item) -> Ts,
+        Ts: Ord + Clone + Add<L, Output = Ts>,
+        L: Clone;
+}
+
+impl<T: Iterator> ReorderIterTool for T {
+    fn reorder_by<'a, P, F, Ts, L>(
+        self,
+        partitioner: &'a P,
+        timestamp: F,
+        allowed_lateness: L,
+        late_policy: LatePolicy<'a, Self::Item>,
+    ) -> ReorderIterator<'a, Self, P, F, Ts, L>
+    where
+        P: Partitioner<Event = Self::Item>,
+        F: Fn(&Self::Item) -> Ts,
+        Ts: Ord + Clone + Add<L, Output = Ts>,
+        L: Clone,
+    {
+        ReorderIterator {
+            iter: self,
+            partitioner,
+            timestamp,
+            allowed_lateness,
+            idle_timeout: None,
+            late_policy,
+            buffer: ReorderBuffer::default(),
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "Ts: Deserialize<'de> + Ord, E: Deserialize<'de>"))]
+struct ReorderState<Ts, E> {
+    // the latest timestamp with the arrival of its event
+    latest: (Ts, u64),
+    // events by timestamp and arrival, so the events with equal timestamps keep their order
+    buffered: BTreeMap<(Ts, u64), E>,
+}
+
+/// Keeps the events until the watermarks pass them, is used by `ReorderIterator` and `Engine`.
+/// Besides the watermark of every partition there is the global one, the latest timestamp of
+/// all partitions minus the idle timeout. It releases the events of quiet partitions and evicts
+/// the partitions it has passed, so their events are late afterwards.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    deserialize = "K: Deserialize<'de> + Eq + Hash, Ts: Deserialize<'de> + Ord, \
+    E: Deserialize<'de>"
+))]
+pub(crate) struct ReorderBuffer<K, Ts, E> {
+    partitions: HashMap<K, ReorderState<Ts, E>>,
+    // keys of the buffered events and of the partitions by their latest timestamp, for the
+    // global watermark
+    buffered: BTreeMap<(Ts, u64), K>,
+    by_latest: BTreeMap<(Ts, u64), K>,
+    latest: Option<Ts>,
+    next_seq: u64,
+}
+
+impl<K, Ts, E> Default for ReorderBuffer<K, Ts, E> {
+    fn default() -> Self {
+        ReorderBuffer {
+            partitions: HashMap::new(),
+            buffered: BTreeMap::new(),
+            by_latest: BTreeMap::new(),
+            latest: None,
+            next_seq: 0,
+        }
+    }
+}
+
+impl<K, Ts, E> ReorderBuffer<K, Ts, E>
+where
+    K: Clone + Eq + Hash,
+    Ts: Ord + Clone,
+{
+    /// Buffers the event and appends the events released by the watermarks to `ready`. The late
+    /// event is returned back.
+    pub(crate) fn push<L>(
+        &mut self,
+        key: K,
+        ts: Ts,
+        event: E,
+        allowed_lateness: &L,
+        idle_timeout: Option<&L>,
+        ready: &mut VecDeque<(K, E)>,
+    ) -> Option<E>
+    where
+        Ts: Add<L, Output = Ts>,
+        L: Clone,
+    {
+        let behind = |ts: &Ts, lag: &L, latest: &Ts| ts.clone() + lag.clone() < *latest;
+        let late_globally = match (idle_timeout, &self.latest) {
+            (Some(idle_timeout), Some(latest)) => behind(&ts, idle_timeout, latest),
+            _ => false,
+        };
+        let late = late_globally
+            || self
+                .partitions
+                .get(&key)
+                .is_some_and(|state| behind(&ts, allowed_lateness, &state.latest.0));
+        if late {
+            return Some(event);
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.latest.as_ref().is_none_or(|latest| ts > *latest) {
+            self.latest = Some(ts.clone());
+        }
+        if !self.partitions.contains_key(&key) {
+            self.by_latest.insert((ts.clone(), seq), key.clone());
+        }
+        let state = self
+            .partitions
+            .entry(key.clone())
+            .or_insert_with(|| ReorderState {
+                latest: (ts.clone(), seq),
+                buffered: BTreeMap::new(),
+            });
+        if ts > state.latest.0 {
+            self.by_latest.remove(&state.latest);
+            state.latest = (ts.clone(), seq);
+            self.by_latest.insert((ts.clone(), seq), key.clone());
+        }
+        state.buffered.insert((ts.clone(), seq), event);
+        self.buffered.insert((ts, seq), key.clone());
+
+        while let Some(entry) = state.buffered.first_entry() {
+            if entry.key().0.clone() + allowed_lateness.clone() > state.latest.0 {
+                break;
+            }
+            self.buffered.remove(entry.key());
+            ready.push_back((key.clone(), entry.remove()));
+        }
+
+        if let (Some(idle_timeout), Some(latest)) = (idle_timeout, &self.latest) {
+            while let Some(entry) = self.buffered.first_entry() {
+                if entry.key().0.clone() + idle_timeout.clone() > *latest {
+                    break;
+                }
+                let (ts_seq, key) = entry.remove_entry();
+                let state = self.partitions.get_mut(&key).expect("Illegal state");
+                let event = state.buffered.remove(&ts_seq).expect("Illegal state");
+                ready.push_back((key, event));
+            }
+            // all events of these partitions are released already
+            while let Some(entry) = self.by_latest.first_entry() {
+                if entry.key().0.clone() + idle_timeout.clone() > *latest {
+                    break;
+                }
+                self.partitions.remove(&entry.remove());
+            }
+        }
+        None
+    }
+
+    /// Returns all buffered events in the order of timestamps, no more events are expected.
+    pub(crate) fn finish(&mut self) -> Vec<(K, E)> {
+        let partitions = &mut self.partitions;
+        let rest = std::mem::take(&mut self.buffered)
+            .into_iter()
+            .map(|(ts_seq, key)| {
+                let state = partitions.get_mut(&key).expect("Illegal state");
+                let event = state.buffered.remove(&ts_seq).expect("Illegal state");
+                (key, event)
+            })
+            .collect();
+        *self = ReorderBuffer::default();
+        rest
+    }
+}
+
+/// Reorders the events of the query by `SimpleMachineMapper::with_event_time` with the options
+/// of `EngineConfig`, is used by `PartitionIterator` and `Engine`.
+pub(crate) struct EventOrder<'a, K, E> {
+    pub(crate) event_time: &'a EventTime<E>,
+    pub(crate) on_late_event: Option<&'a LateEventHook<K, E>>,
+    pub(crate) config: &'a EngineConfig,
+}
+
+impl<K, E> Clone for EventOrder<'_, K, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, E> Copy for EventOrder<'_, K, E> {}
+
+impl<K: Clone + Eq + Hash, E> EventOrder<'_, K, E> {
+    /// Buffers the event and appends the events released by the watermarks to `ready`. The late
+    /// event is passed to the callback and then handled by `EngineConfig::late_event_policy`.
+    pub(crate) fn push(
+        &self,
+        buffer: &mut ReorderBuffer<K, u64, E>,
+        key: K,
+        event: E,
+        ready: &mut VecDeque<(K, E)>,
+    ) {
+        let late = buffer.push(
+            key.clone(),
+            (self.event_time)(&event),
+            event,
+            &self.config.allowed_lateness(),
+            self.config.reorder_idle_timeout().as_ref(),
+            ready,
+        );
+        if let Some(event) = late {
+            if let Some(on_late_event) = self.on_late_event {
+                on_late_event(&key, &event);
+            }
+            if self.config.late_event_policy() == LateEventPolicy::Reprocess {
+                ready.push_back((key, event));
+            }
+        }
+    }
+}
+
+/// Returns the events of every partition in the order of their timestamps. Watermarks of the
+/// partitions are kept to recognize late events, until the global watermark evicts them.
+pub struct ReorderIterator<'a, J, Part, F, Ts, L>
+where
+    J: Iterator,
+    Part: Partitioner<Event = J::Item>,
+{
+    iter: J,
+    partitioner: &'a Part,
+    timestamp: F,
+    allowed_lateness: L,
+    idle_timeout: Option<L>,
+    late_policy: LatePolicy<'a, J::Item>,
+    buffer: ReorderBuffer<Part::T, Ts, J::Item>,
+    ready: VecDeque<(Part::T, J::Item)>,
+}
+
+impl<J, Part, F, Ts, L> ReorderIterator<'_, J, Part, F, Ts, L>
+where
+    J: Iterator,
+    Part: Partitioner<Event = J::Item>,
+    F: Fn(&J::Item) -> Ts,
+    Ts: Ord + Clone + Add<L, Output = Ts>,
+    L: Clone,
+{
+    /// Sets the lag of the global watermark behind the latest timestamp of all partitions. The
+    /// events it passes are returned even if their partition has no newer events, e.g. it has
+    /// become quiet, and the later events of such partition are late.
+    pub fn with_idle_timeout(mut self, idle_timeout: L) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    fn push(&mut self, event: J::Item) {
+        let key = self.partitioner.partition_key(&event);
+        let ts = (self.timestamp)(&event);
+        let late = self.buffer.push(
+            key.clone(),
+            ts,
+            event,
+            &self.allowed_lateness,
+            self.idle_timeout.as_ref(),
+            &mut self.ready,
+        );
+        if let Some(event) = late {
+            match &mut self.late_policy {
+                LatePolicy::Drop => {}
+                LatePolicy::SideOutput(sink) => sink(event),
+                LatePolicy::Reprocess => self.ready.push_back((key, event)),
+            }
+        }
+    }
+}
+
+impl<J, Part, F, Ts, L> Iterator for ReorderIterator<'_, J, Part, F, Ts, L>
+where
+    J: Iterator,
+    Part: Partitioner<Event = J::Item>,
+    F: Fn(&J::Item) -> Ts,
+    Ts: Ord + Clone + Add<L, Output = Ts>,
+    L: Clone,
+{
+    type Item = J::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            match self.iter.next() {
+                Some(event) => self.push(event),
+                None => {
+                    // the input is over, so the rest of the events of all partitions are
+                    // returned in the order of timestamps
+                    self.ready.extend(self.buffer.finish());
+                    break;
+                }
+            }
+        }
+        self.ready.pop_front().map(|(_, event)| event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::partitioner::*;
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct TestEvent {
+        partition_key: u64,
+        ts: u64,
+    }
+
+    fn events(input: &[(u64, u64)]) -> Vec<TestEvent> {
+        input
+            .iter()
+            .map(|&(partition_key, ts)| TestEvent { partition_key, ts })
+            .collect()
+    }
+
+    #[test]
+    fn reorders_by_timestamp() {
+        let input = events(&[(0, 2), (1, 5), (0, 1), (0, 3), (1, 4), (0, 6)]);
+        let partitioner = FunctionPartitioner::new(|e: &TestEvent| e.partition_key);
+        let output: Vec<_> = input
+            .iter()
+            .reorder_by(&partitioner, |e| e.ts, 2, LatePolicy::Drop)
+            .map(|e| (e.partition_key, e.ts))
+            .collect();
+        assert_eq!(output, vec![(0, 1), (0, 2), (0, 3), (1, 4), (1, 5), (0, 6)]);
+    }
+
+    #[test]
+    fn applies_late_policy() {
+        let input = events(&[(0, 5), (0, 1), (0, 4), (0, 6)]);
+        let partitioner = FunctionPartitioner::new(|e: &TestEvent| e.partition_key);
+        let run = |late_policy| -> Vec<u64> {
+            input
+                .iter()
+                .reorder_by(&partitioner, |e| e.ts, 2, late_policy)
+                .map(|e| e.ts)
+                .collect()
+        };
+
+        assert_eq!(run(LatePolicy::Drop), vec![4, 5, 6]);
+        assert_eq!(run(LatePolicy::Reprocess), vec![1, 4, 5, 6]);
+        let mut late = vec![];
+        let output = run(LatePolicy::SideOutput(Box::new(|e: &TestEvent| {
+            late.push(e.ts)
+        })));
+        assert_eq!(output, vec![4, 5, 6]);
+        assert_eq!(late, vec![1]);
+    }
+
+    #[test]
+    fn releases_quiet_partitions() {
+        let input = events(&[(0, 1), (0, 3), (0, 2), (1, 4), (1, 10), (1, 20), (0, 5)]);
+        let partitioner = FunctionPartitioner::new(|e: &TestEvent| e.partition_key);
+        let mut late = vec![];
+        let output: Vec<_> = input
+            .iter()
+            .reorder_by(
+                &partitioner,
+                |e| e.ts,
+                5,
+                LatePolicy::SideOutput(Box::new(|e: &TestEvent| late.push(e.ts))),
+            )
+            .with_idle_timeout(8)
+            .map(|e| (e.partition_key, e.ts))
+            .collect();
+        // partition 0 is passed by the global watermark, so its last event is late
+        assert_eq!(
+            output,
+            vec![(1, 4), (0, 1), (0, 2), (1, 10), (0, 3), (1, 20)]
+        );
+        assert_eq!(late, vec![5]);
+
+        let mut buffer = ReorderBuffer::default();
+        let mut ready = VecDeque::new();
+        for e in &input[..6] {
+            buffer.push(e.partition_key, e.ts, e.ts, &5, Some(&8), &mut ready);
+        }
+        assert_eq!(buffer.partitions.len(), 1);
+        assert_eq!(buffer.finish(), vec![(1, 20)]);
+    }
+}
@@ -1,5 +1,7 @@
 use std::cmp::{max, min};
 
+use serde::{Deserialize, Serialize};
+
 use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
 
 #[derive(Clone)]
@@ -18,7 +20,7 @@ where
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct AndThenPatternState<S1: Default, S2: Default> {
     first_state: S1,
     first_queue: PQueue<()>,
@@ -57,7 +59,8 @@ where
             &mut state.second_state,
         );
 
-        let offset = self.second.width() + 1;
+        // the width of unbounded patterns is `Idx::MAX`, their results can't be aligned
+        let offset = self.second.width().saturating_add(1);
 
         // while we have results in first_queue
         while let Some(IdxValue {
@@ -66,8 +69,8 @@ where
             result: first_result,
         }) = state.first_queue.head_option()
         {
-            let result_begin = first_start + offset;
-            let result_end = first_end + offset;
+            let result_begin = first_start.saturating_add(offset);
+            let result_end = first_end.saturating_add(offset);
 
             //todo should we emit Failure for the beginning of stream?
             // state.second_queue.rewind_to(dbg!(result_begin));
@@ -92,7 +95,10 @@ where
                             (PatternResult::Success(()), PatternResult::Success(())) => {
                                 PatternResult::Success(())
                             }
-                            _ => PatternResult::Failure,
+                            (PatternResult::Failure, _) | (_, PatternResult::Failure) => {
+                                PatternResult::Failure
+                            }
+                            _ => PatternResult::Unknown,
                         };
                         queue.enqueue_joined(IdxValue::new(*start, end, result));
                         // update second queue
@@ -103,7 +109,9 @@ where
                 }
             }
             // update first queue
-            state.first_queue.rewind_to(end + 1 - offset);
+            state
+                .first_queue
+                .rewind_to(end.saturating_add(1).saturating_sub(offset));
         }
     }
 
@@ -111,6 +119,7 @@ where
 
     fn width(&self) -> Self::W {
         // we add 1 here due to results of first and second must be divided by one message.
-        1u64 + self.first.width() + self.second.width()
+        1u64.saturating_add(self.first.width())
+            .saturating_add(self.second.width())
     }
 }
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::tsp::patterns::pattern::{IdxValue, PQueue, Pattern, PatternResult};
 use crate::tsp::patterns::Idx;
 
@@ -15,7 +17,7 @@ where
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct AssertPatternState<S: Default> {
     inner_state: S,
     inner_queue: PQueue<bool>,
@@ -51,6 +53,7 @@ where
                     PatternResult::Failure | PatternResult::Success(false) => {
                         PatternResult::Failure
                     }
+                    PatternResult::Unknown => PatternResult::Unknown,
                     PatternResult::Success(true) => PatternResult::Success(()),
                 },
             ));
@@ -1,5 +1,7 @@
 use std::cmp::{max, min};
 
+use serde::{Deserialize, Serialize};
+
 use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
 
 #[derive(Clone)]
@@ -26,12 +28,13 @@ where
             (PatternResult::Success(lt), PatternResult::Success(rt)) => {
                 PatternResult::Success((self.func)(lt, rt))
             }
-            _ => PatternResult::Failure,
+            (PatternResult::Failure, _) | (_, PatternResult::Failure) => PatternResult::Failure,
+            _ => PatternResult::Unknown,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct BiPatternState<S1: Default, T1: Clone, S2: Default, T2: Clone> {
     left: S1,
     right: S2,
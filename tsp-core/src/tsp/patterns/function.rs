@@ -3,6 +3,8 @@ use std::marker::PhantomData;
 use crate::tsp::patterns::common::NoState;
 use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
 
+/// Returns the result of `func` for every event. Missing values (`None` or NaN) are returned as
+/// they are, wrap the pattern into `MissingPattern` to apply a `MissingPolicy` to them.
 #[derive(Clone)]
 pub struct FunctionPattern<E, F, T>
 where
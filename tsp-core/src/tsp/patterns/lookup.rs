@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Error, ErrorKind};
+use std::sync::Arc;
+
+use crate::tsp::partitioners::Partitioner;
+use crate::tsp::patterns::common::NoState;
+use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
+
+/// Looks up the value for every event in the static reference table. The key is taken from
+/// the event by `key`, any `Partitioner` can be used here, so the same partitioner as in the
+/// query gives lookup by the partition key. Missing keys produce `Failure`.
+#[derive(Clone)]
+pub struct LookupPattern<K, V>
+where
+    K: Partitioner,
+{
+    key: K,
+    table: Arc<HashMap<K::T, V>>,
+}
+
+impl<K, V> LookupPattern<K, V>
+where
+    K: Partitioner,
+    V: Clone,
+{
+    pub fn new(key: K, table: HashMap<K::T, V>) -> Self {
+        LookupPattern {
+            key,
+            table: Arc::new(table),
+        }
+    }
+
+    /// Allows to share one table between several patterns.
+    pub fn shared(key: K, table: Arc<HashMap<K::T, V>>) -> Self {
+        LookupPattern { key, table }
+    }
+}
+
+impl<K, V> Pattern for LookupPattern<K, V>
+where
+    K: Partitioner,
+    V: Clone + PartialEq,
+{
+    type State = NoState;
+    type Event = K::Event;
+    type T = V;
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        _state: &mut Self::State,
+    ) {
+        event.iter().enumerate().for_each(|(idx, e)| {
+            let result = match self.table.get(&self.key.partition_key(e)) {
+                Some(value) => PatternResult::Success(value.clone()),
+                None => PatternResult::Failure,
+            };
+            queue.enqueue_joined(IdxValue::new(
+                start_idx + idx as Idx,
+                start_idx + idx as Idx,
+                result,
+            ));
+        })
+    }
+
+    type W = Idx;
+
+    fn width(&self) -> Self::W {
+        0
+    }
+}
+
+/// Reads reference table from comma separated lines. `parse` receives the fields of every
+/// line and returns key and value or description of the problem.
+pub fn read_csv_table<R, K, V, F>(
+    reader: R,
+    has_header: bool,
+    parse: F,
+) -> std::io::Result<HashMap<K, V>>
+where
+    R: BufRead,
+    K: Eq + std::hash::Hash,
+    F: Fn(&[&str]) -> Result<(K, V), String>,
+{
+    let mut table = HashMap::new();
+    for (line_number, line) in reader.lines().enumerate().skip(has_header as usize) {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        let (key, value) = parse(&fields).map_err(|reason| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: {}", line_number + 1, reason),
+            )
+        })?;
+        table.insert(key, value);
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+
+    struct TE {
+        model: &'static str,
+    }
+
+    #[test]
+    fn looks_up_values_from_csv_table() {
+        let csv = "model,max\na1,80\nb2, 95\n";
+        let table = read_csv_table(csv.as_bytes(), true, |fields| {
+            let max = fields[1].parse::<u32>().map_err(|e| e.to_string())?;
+            Ok((fields[0].to_string(), max))
+        })
+        .unwrap();
+        let pattern = LookupPattern::new(
+            FunctionPartitioner::new(|e: &TE| e.model.to_string()),
+            table,
+        );
+
+        let events = [TE { model: "b2" }, TE { model: "c3" }, TE { model: "a1" }];
+        let events: Vec<&TE> = events.iter().collect();
+        let mut queue = PQueue::default();
+        pattern.apply(0, &events, &mut queue, &mut NoState);
+
+        let results: Vec<PatternResult<u32>> = std::iter::from_fn(|| queue.dequeue_option())
+            .map(|idx_value| idx_value.result)
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                PatternResult::Success(95),
+                PatternResult::Failure,
+                PatternResult::Success(80)
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_broken_lines() {
+        let error = read_csv_table("a1,80\na2,x\n".as_bytes(), false, |fields| {
+            let max = fields[1].parse::<u32>().map_err(|e| e.to_string())?;
+            Ok((fields[0].to_string(), max))
+        })
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
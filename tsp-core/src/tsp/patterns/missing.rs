@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::function::FunctionPattern;
+use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
+
+/// Field value which can be missing: `None` or NaN.
+pub trait Nullable {
+    type Value;
+    fn into_value(self) -> Option<Self::Value>;
+}
+
+impl<T> Nullable for Option<T> {
+    type Value = T;
+
+    fn into_value(self) -> Option<T> {
+        self
+    }
+}
+
+impl Nullable for f64 {
+    type Value = f64;
+
+    fn into_value(self) -> Option<f64> {
+        if self.is_nan() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+}
+
+impl Nullable for f32 {
+    type Value = f32;
+
+    fn into_value(self) -> Option<f32> {
+        if self.is_nan() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+}
+
+/// Linear interpolation between two present values, `fraction` is in `(0, 1)`.
+pub trait Interpolate {
+    fn interpolate(from: &Self, to: &Self, fraction: f64) -> Self;
+}
+
+macro_rules! interpolate_numeric {
+    ($($t:ty),*) => {
+        $(
+            impl Interpolate for $t {
+                fn interpolate(from: &Self, to: &Self, fraction: f64) -> Self {
+                    (*from as f64 + (*to as f64 - *from as f64) * fraction) as $t
+                }
+            }
+        )*
+    };
+}
+
+interpolate_numeric!(f64, f32, i64, i32, i16, i8, u64, u32, u16, u8);
+
+/// What `MissingPattern` returns for the events where the value is missing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingPolicy {
+    /// `PatternResult::Failure`.
+    Failure,
+    /// `PatternResult::Unknown`, so the event neither fires nor fails the rule.
+    Skip,
+    /// The last present value of the partition, `Unknown` if there was no such value.
+    CarryForward,
+}
+
+enum Fill<T> {
+    Policy(MissingPolicy),
+    Interpolate(fn(&T, &T, f64) -> T),
+}
+
+impl<T> Clone for Fill<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Fill<T> {}
+
+/// Applies the `MissingPolicy` to the results of the inner pattern, e.g. `FunctionPattern`
+/// returning `Option` or a float, so missing values are never passed further. `Unknown` results
+/// of the inner pattern are treated as missing values.
+#[derive(Clone)]
+pub struct MissingPattern<P, T> {
+    inner: P,
+    fill: Fill<T>,
+}
+
+/// Same as `FunctionPattern` for the fields that can be missing (`Option` or NaN).
+pub type FieldPattern<E, F, N> = MissingPattern<FunctionPattern<E, F, N>, <N as Nullable>::Value>;
+
+impl<P, N, T> MissingPattern<P, T>
+where
+    P: Pattern<T = N>,
+    N: Nullable<Value = T> + Clone,
+{
+    pub fn new(inner: P, policy: MissingPolicy) -> Self {
+        MissingPattern {
+            inner,
+            fill: Fill::Policy(policy),
+        }
+    }
+
+    /// Missing values are replaced by linear interpolation between the neighbour present values.
+    /// Results are delayed until the next present value arrives, missing values at the
+    /// beginning of partition are `Unknown`.
+    pub fn interpolated(inner: P) -> Self
+    where
+        T: Interpolate,
+    {
+        MissingPattern {
+            inner,
+            fill: Fill::Interpolate(T::interpolate),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingPatternState<S, N: Clone, T> {
+    inner_state: S,
+    inner_queue: PQueue<N>,
+    last: Option<(Idx, T)>,
+    missing_since: Option<Idx>,
+}
+
+impl<S: Default, N: Clone, T> Default for MissingPatternState<S, N, T> {
+    fn default() -> Self {
+        MissingPatternState {
+            inner_state: S::default(),
+            inner_queue: PQueue::default(),
+            last: None,
+            missing_since: None,
+        }
+    }
+}
+
+impl<P, N, T> Pattern for MissingPattern<P, T>
+where
+    P: Pattern<T = N, W = Idx>,
+    N: Nullable<Value = T> + Clone,
+    T: Clone + PartialEq,
+{
+    type State = MissingPatternState<P::State, N, T>;
+    type Event = P::Event;
+    type T = T;
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        state: &mut Self::State,
+    ) {
+        self.inner.apply(
+            start_idx,
+            event,
+            &mut state.inner_queue,
+            &mut state.inner_state,
+        );
+        while let Some(IdxValue { start, end, result }) = state.inner_queue.dequeue_option() {
+            let value = match result {
+                PatternResult::Success(value) => value.into_value(),
+                PatternResult::Unknown => None,
+                PatternResult::Failure => {
+                    // the interpolation has no next value, so the delayed results are unknown
+                    if let Some(missing_since) = state.missing_since.take() {
+                        queue.enqueue_joined(IdxValue::new(
+                            missing_since,
+                            start - 1,
+                            PatternResult::Unknown,
+                        ));
+                    }
+                    queue.enqueue_joined(IdxValue::new(start, end, PatternResult::Failure));
+                    continue;
+                }
+            };
+            let value = match (value, self.fill) {
+                (Some(value), _) => value,
+                (None, Fill::Interpolate(_)) => {
+                    state.missing_since.get_or_insert(start);
+                    continue;
+                }
+                (None, Fill::Policy(policy)) => {
+                    let result = match (policy, &state.last) {
+                        (MissingPolicy::Failure, _) => PatternResult::Failure,
+                        (MissingPolicy::CarryForward, Some((_, last))) => {
+                            PatternResult::Success(last.clone())
+                        }
+                        _ => PatternResult::Unknown,
+                    };
+                    queue.enqueue_joined(IdxValue::new(start, end, result));
+                    continue;
+                }
+            };
+
+            if let (Some(missing_since), Fill::Interpolate(interpolate)) =
+                (state.missing_since.take(), self.fill)
+            {
+                match &state.last {
+                    Some((last_idx, last)) => {
+                        for missing_idx in missing_since..start {
+                            let fraction =
+                                (missing_idx - last_idx) as f64 / (start - last_idx) as f64;
+                            queue.enqueue_joined(IdxValue::new(
+                                missing_idx,
+                                missing_idx,
+                                PatternResult::Success(interpolate(last, &value, fraction)),
+                            ));
+                        }
+                    }
+                    None => {
+                        queue.enqueue_joined(IdxValue::new(
+                            missing_since,
+                            start - 1,
+                            PatternResult::Unknown,
+                        ));
+                    }
+                }
+            }
+
+            queue.enqueue_joined(IdxValue::new(
+                start,
+                end,
+                PatternResult::Success(value.clone()),
+            ));
+            state.last = Some((end, value));
+        }
+    }
+
+    type W = Idx;
+
+    fn width(&self) -> Self::W {
+        self.inner.width()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<P>(pattern: P, events: &[f64]) -> Vec<PatternResult<f64>>
+    where
+        P: Pattern<Event = f64, T = f64>,
+    {
+        let mut state = Default::default();
+        let mut queue = PQueue::default();
+        for (i, chunk) in events.chunks(2).enumerate() {
+            pattern.apply(i as Idx * 2, chunk, &mut queue, &mut state);
+        }
+        std::iter::from_fn(|| queue.dequeue_option())
+            .flat_map(|IdxValue { start, end, result }| (start..=end).map(move |_| result.clone()))
+            .collect()
+    }
+
+    fn field() -> FunctionPattern<f64, impl Fn(&f64) -> f64, f64> {
+        FunctionPattern::new(|e: &f64| *e)
+    }
+
+    #[test]
+    fn applies_missing_policies() {
+        let events = [f64::NAN, 1.0, f64::NAN, f64::NAN, 4.0];
+        let s = PatternResult::Success;
+        assert_eq!(
+            run(FieldPattern::new(field(), MissingPolicy::Failure), &events),
+            vec![
+                PatternResult::Failure,
+                s(1.0),
+                PatternResult::Failure,
+                PatternResult::Failure,
+                s(4.0)
+            ]
+        );
+        assert_eq!(
+            run(FieldPattern::new(field(), MissingPolicy::Skip), &events),
+            vec![
+                PatternResult::Unknown,
+                s(1.0),
+                PatternResult::Unknown,
+                PatternResult::Unknown,
+                s(4.0)
+            ]
+        );
+        assert_eq!(
+            run(
+                FieldPattern::new(field(), MissingPolicy::CarryForward),
+                &events
+            ),
+            vec![PatternResult::Unknown, s(1.0), s(1.0), s(1.0), s(4.0)]
+        );
+        assert_eq!(
+            run(FieldPattern::interpolated(field()), &events),
+            vec![PatternResult::Unknown, s(1.0), s(2.0), s(3.0), s(4.0)]
+        );
+    }
+}
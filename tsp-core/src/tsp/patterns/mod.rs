@@ -4,7 +4,11 @@ pub mod bi_pattern;
 pub mod common;
 pub mod constant;
 pub mod function;
+pub mod lookup;
+pub mod missing;
 pub mod pattern;
+pub mod sequence;
+pub mod string;
 pub mod window;
 
 pub use self::and_then::*;
@@ -13,5 +17,9 @@ pub use self::bi_pattern::*;
 pub use self::common::*;
 pub use self::constant::*;
 pub use self::function::*;
+pub use self::lookup::*;
+pub use self::missing::*;
 pub use self::pattern::*;
+pub use self::sequence::*;
+pub use self::string::*;
 pub use self::window::*;
@@ -1,6 +1,8 @@
 use std::cmp::max;
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
 pub trait Pattern {
     type State: Default;
     type Event;
@@ -24,12 +26,15 @@ impl Width for u64 {}
 
 pub type Idx = u64;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PatternResult<T: Sized>
 where
     T: Clone,
 {
     Failure,
+    /// There is not enough data to tell whether the pattern succeeded or not, e.g. at the
+    /// beginning of a partition.
+    Unknown,
     Success(T), //todo make result fixed size
 }
 
@@ -40,13 +45,14 @@ where
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (PatternResult::Failure, PatternResult::Failure) => true,
+            (PatternResult::Unknown, PatternResult::Unknown) => true,
             (PatternResult::Success(a), PatternResult::Success(b)) if a == b => true,
             _ => false,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IdxValue<T: Clone> {
     pub start: Idx,
     pub end: Idx,
@@ -59,7 +65,7 @@ impl<T: Clone> IdxValue<T> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PQueue<T: Clone> {
     queue: std::collections::VecDeque<IdxValue<T>>,
 }
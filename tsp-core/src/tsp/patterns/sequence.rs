@@ -0,0 +1,746 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
+
+/// Regular expression over symbols. Every symbol is an index into the list of unit patterns
+/// of a `SequencePattern`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceExpr {
+    Symbol(usize),
+    Concat(Vec<SequenceExpr>),
+    Alternation(Vec<SequenceExpr>),
+    Repeat {
+        inner: Box<SequenceExpr>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceParseError {
+    pub position: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for SequenceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.reason, self.position)
+    }
+}
+
+impl std::error::Error for SequenceParseError {}
+
+impl SequenceExpr {
+    /// Parses expressions like `A B* C{2,5}` or `(A | B)+ C?`. Letters are symbols, their
+    /// indices are positions of the letters in `symbols`. Whitespace is ignored.
+    pub fn parse(expr: &str, symbols: &[char]) -> Result<SequenceExpr, SequenceParseError> {
+        let mut parser = Parser {
+            chars: expr.chars().filter(|c| !c.is_whitespace()).collect(),
+            pos: 0,
+            symbols,
+        };
+        let result = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        Ok(result)
+    }
+
+    fn min_len(&self) -> u64 {
+        match self {
+            SequenceExpr::Symbol(_) => 1,
+            SequenceExpr::Concat(items) => items.iter().map(|e| e.min_len()).sum(),
+            SequenceExpr::Alternation(items) => {
+                items.iter().map(|e| e.min_len()).min().unwrap_or(0)
+            }
+            SequenceExpr::Repeat { inner, min, .. } => inner.min_len() * *min as u64,
+        }
+    }
+
+    fn max_len(&self) -> Option<u64> {
+        match self {
+            SequenceExpr::Symbol(_) => Some(1),
+            SequenceExpr::Concat(items) => items.iter().map(|e| e.max_len()).sum(),
+            SequenceExpr::Alternation(items) => items
+                .iter()
+                .map(|e| e.max_len())
+                .try_fold(0, |acc, l| l.map(|l| acc.max(l))),
+            SequenceExpr::Repeat { inner, max, .. } => Some(inner.max_len()? * (*max)? as u64),
+        }
+    }
+
+    fn max_symbol(&self) -> Option<usize> {
+        match self {
+            SequenceExpr::Symbol(s) => Some(*s),
+            SequenceExpr::Concat(items) | SequenceExpr::Alternation(items) => {
+                items.iter().filter_map(|e| e.max_symbol()).max()
+            }
+            SequenceExpr::Repeat { inner, .. } => inner.max_symbol(),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    symbols: &'a [char],
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &str) -> SequenceParseError {
+        SequenceParseError {
+            position: self.pos,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn alternation(&mut self) -> Result<SequenceExpr, SequenceParseError> {
+        let mut items = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            items.push(self.concat()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().expect("Illegal state")
+        } else {
+            SequenceExpr::Alternation(items)
+        })
+    }
+
+    fn concat(&mut self) -> Result<SequenceExpr, SequenceParseError> {
+        let mut items = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            items.push(self.repeat()?);
+        }
+        match items.len() {
+            0 => Err(self.error("empty expression")),
+            1 => Ok(items.pop().expect("Illegal state")),
+            _ => Ok(SequenceExpr::Concat(items)),
+        }
+    }
+
+    fn repeat(&mut self) -> Result<SequenceExpr, SequenceParseError> {
+        let mut expr = self.atom()?;
+        while let Some(c) = self.peek() {
+            let (min, max) = match c {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                '{' => self.bounds()?,
+                _ => break,
+            };
+            if c != '{' {
+                self.pos += 1;
+            }
+            expr = SequenceExpr::Repeat {
+                inner: Box::new(expr),
+                min,
+                max,
+            };
+        }
+        Ok(expr)
+    }
+
+    // parses `{n}`, `{n,}` and `{n,m}`
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), SequenceParseError> {
+        self.pos += 1;
+        let min = self
+            .number()?
+            .ok_or_else(|| self.error("expected number"))?;
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.number()?
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some('}') {
+            return Err(self.error("expected '}'"));
+        }
+        self.pos += 1;
+        match max {
+            Some(max) if max < min => Err(self.error("upper bound is less than lower bound")),
+            _ => Ok((min, max)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, SequenceParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .map(Some)
+            .map_err(|_| self.error("number is too big"))
+    }
+
+    fn atom(&mut self) -> Result<SequenceExpr, SequenceParseError> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.alternation()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("expected ')'"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_alphabetic() => {
+                let symbol = self
+                    .symbols
+                    .iter()
+                    .position(|s| *s == c)
+                    .ok_or_else(|| self.error("unknown symbol"))?;
+                self.pos += 1;
+                Ok(SequenceExpr::Symbol(symbol))
+            }
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum NfaState {
+    Symbol(usize, usize),
+    Split(usize, usize),
+    Match,
+}
+
+/// Thompson NFA compiled from `SequenceExpr`.
+#[derive(Debug, Clone)]
+struct Nfa {
+    states: Vec<NfaState>,
+    start: usize,
+}
+
+impl Nfa {
+    fn compile(expr: &SequenceExpr) -> Nfa {
+        let mut nfa = Nfa {
+            states: vec![NfaState::Match],
+            start: 0,
+        };
+        nfa.start = nfa.add(expr, 0);
+        nfa
+    }
+
+    fn push(&mut self, state: NfaState) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    // compiles `expr` so that it continues to `next`, returns the entry state
+    fn add(&mut self, expr: &SequenceExpr, next: usize) -> usize {
+        match expr {
+            SequenceExpr::Symbol(s) => self.push(NfaState::Symbol(*s, next)),
+            SequenceExpr::Concat(items) => items
+                .iter()
+                .rev()
+                .fold(next, |next, item| self.add(item, next)),
+            SequenceExpr::Alternation(items) => {
+                let starts: Vec<usize> = items.iter().map(|item| self.add(item, next)).collect();
+                starts
+                    .into_iter()
+                    .rev()
+                    .fold(None, |rest, start| match rest {
+                        None => Some(start),
+                        Some(rest) => Some(self.push(NfaState::Split(start, rest))),
+                    })
+                    .unwrap_or(next)
+            }
+            SequenceExpr::Repeat { inner, min, max } => {
+                let mut entry = match max {
+                    Some(max) => (*min..*max).fold(next, |next, _| {
+                        let body = self.add(inner, next);
+                        self.push(NfaState::Split(body, next))
+                    }),
+                    None => {
+                        let split = self.push(NfaState::Split(next, next));
+                        let body = self.add(inner, split);
+                        self.states[split] = NfaState::Split(body, next);
+                        split
+                    }
+                };
+                for _ in 0..*min {
+                    entry = self.add(inner, entry);
+                }
+                entry
+            }
+        }
+    }
+
+    // adds the thread in `state` and everything reachable from it by epsilon transitions, the
+    // threads keep the index where their match has started
+    fn add_closure(
+        &self,
+        state: usize,
+        start: Idx,
+        set: &mut Vec<(usize, Idx)>,
+        visited: &mut Vec<bool>,
+    ) {
+        if visited[state] {
+            return;
+        }
+        visited[state] = true;
+        match self.states[state] {
+            NfaState::Split(a, b) => {
+                self.add_closure(a, start, set, visited);
+                self.add_closure(b, start, set, visited);
+            }
+            _ => set.push((state, start)),
+        }
+    }
+
+    // consumes the event `idx`: every index can be the beginning of a new match. Threads are
+    // ordered by their starts, so the state reached by several threads keeps the earliest one.
+    fn step<F>(
+        &self,
+        threads: &[(usize, Idx)],
+        idx: Idx,
+        visited: &mut Vec<bool>,
+        matched: F,
+    ) -> Vec<(usize, Idx)>
+    where
+        F: Fn(usize) -> bool,
+    {
+        visited.iter_mut().for_each(|v| *v = false);
+        let mut current = vec![];
+        for (s, start) in threads {
+            self.add_closure(*s, *start, &mut current, visited);
+        }
+        self.add_closure(self.start, idx, &mut current, visited);
+
+        visited.iter_mut().for_each(|v| *v = false);
+        let mut next = vec![];
+        for (s, start) in current {
+            if let NfaState::Symbol(symbol, to) = self.states[s] {
+                if matched(symbol) {
+                    self.add_closure(to, start, &mut next, visited);
+                }
+            }
+        }
+        next
+    }
+
+    // returns the start of the earliest match accepted by the threads
+    fn accepts(&self, threads: &[(usize, Idx)]) -> Option<Idx> {
+        threads
+            .iter()
+            .find(|(s, _)| matches!(self.states[*s], NfaState::Match))
+            .map(|(_, start)| *start)
+    }
+}
+
+/// Matches a regular expression over unit patterns (symbols). The result is `Success` for all
+/// events of every matched sequence, the earliest start is taken for the matches with the same
+/// end. The result for an index is returned when no running match can cover it anymore. If the
+/// match depends on `Unknown` results of the symbols, the result is `Unknown`.
+///
+/// Symbols of different types may be combined by boxing them as `BoxedSymbol`.
+#[derive(Clone)]
+pub struct SequencePattern<P> {
+    symbols: Vec<P>,
+    nfa: Nfa,
+    width: Idx,
+}
+
+impl<P> SequencePattern<P>
+where
+    P: Pattern<T = ()>,
+{
+    /// Fails if the expression refers to a symbol missing in `symbols` or matches the empty
+    /// sequence.
+    pub fn new(expr: SequenceExpr, symbols: Vec<P>) -> Result<Self, SequenceParseError> {
+        let error = |reason: &str| SequenceParseError {
+            position: 0,
+            reason: reason.to_string(),
+        };
+        if expr.max_symbol().is_some_and(|s| s >= symbols.len()) {
+            return Err(error("unknown symbol"));
+        }
+        if expr.min_len() == 0 {
+            return Err(error("expression matches empty sequence"));
+        }
+        // unbounded expressions like `A+` may cover any number of events
+        let width = expr
+            .max_len()
+            .map_or(Idx::MAX, |max_len| max_len.saturating_sub(1));
+        Ok(SequencePattern {
+            symbols,
+            nfa: Nfa::compile(&expr),
+            width,
+        })
+    }
+
+    /// Creates pattern from expression like `A B* C{2,5}`, where every letter is bound to
+    /// the pattern in `symbols`.
+    pub fn parse(expr: &str, symbols: Vec<(char, P)>) -> Result<Self, SequenceParseError> {
+        let (names, patterns): (Vec<char>, Vec<P>) = symbols.into_iter().unzip();
+        let expr = SequenceExpr::parse(expr, &names)?;
+        SequencePattern::new(expr, patterns)
+    }
+}
+
+/// Unit pattern with erased type of state, allows symbols of different types in one
+/// `SequencePattern`.
+pub trait DynSymbol<E> {
+    fn new_state(&self) -> Box<dyn Any + Send>;
+    fn apply_any(&self, start_idx: Idx, events: &[E], queue: &mut PQueue<()>, state: &mut dyn Any);
+    fn width_any(&self) -> Idx;
+}
+
+impl<P> DynSymbol<P::Event> for P
+where
+    P: Pattern<T = (), W = Idx>,
+    P::State: Send + 'static,
+{
+    fn new_state(&self) -> Box<dyn Any + Send> {
+        Box::new(P::State::default())
+    }
+
+    fn apply_any(
+        &self,
+        start_idx: Idx,
+        events: &[P::Event],
+        queue: &mut PQueue<()>,
+        state: &mut dyn Any,
+    ) {
+        let state = state.downcast_mut().expect("Illegal state");
+        self.apply(start_idx, events, queue, state)
+    }
+
+    fn width_any(&self) -> Idx {
+        self.width()
+    }
+}
+
+pub type BoxedSymbol<'a, E> = Box<dyn DynSymbol<E> + 'a>;
+
+/// State of `BoxedSymbol`. Its type is erased, so unlike the other pattern states it's not
+/// serializable.
+#[derive(Default)]
+pub struct BoxedSymbolState {
+    state: Option<Box<dyn Any + Send>>,
+}
+
+impl<E> Pattern for BoxedSymbol<'_, E> {
+    type State = BoxedSymbolState;
+    type Event = E;
+    type T = ();
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        state: &mut Self::State,
+    ) {
+        let symbol_state = state.state.get_or_insert_with(|| (**self).new_state());
+        (**self).apply_any(start_idx, event, queue, symbol_state.as_mut());
+    }
+
+    type W = Idx;
+
+    fn width(&self) -> Self::W {
+        (**self).width_any()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SequencePatternState<S: Default> {
+    symbol_states: Vec<S>,
+    symbol_queues: Vec<PQueue<()>>,
+    active: Vec<(usize, Idx)>,
+    possible: Vec<(usize, Idx)>,
+    // results of the indices from `resolved` which may still be covered by a running match,
+    // `true` marks the first event of a match which does not overlap the previous one
+    pending: VecDeque<(PatternResult<()>, bool)>,
+    resolved: Idx,
+    next_idx: Idx,
+}
+
+impl<S: Default> Default for SequencePatternState<S> {
+    fn default() -> Self {
+        SequencePatternState {
+            symbol_states: vec![],
+            symbol_queues: vec![],
+            active: vec![],
+            possible: vec![],
+            pending: VecDeque::new(),
+            resolved: 0,
+            next_idx: 0,
+        }
+    }
+}
+
+impl<S: Default> SequencePatternState<S> {
+    // marks the events `[start, end]` of the match, `Success` overrides other results and
+    // `Unknown` overrides `Failure`
+    fn mark(&mut self, start: Idx, end: Idx, result: PatternResult<()>) {
+        let from = start.saturating_sub(self.resolved) as usize;
+        let to = (end - self.resolved) as usize;
+        if start >= self.resolved && result == PatternResult::Success(()) {
+            let (first, starts_match) = &mut self.pending[from];
+            if *first != PatternResult::Success(()) {
+                *starts_match = true;
+            }
+        }
+        for (pending, _) in self.pending.range_mut(from..=to) {
+            match (&pending, &result) {
+                (PatternResult::Success(()), _) => {}
+                (PatternResult::Unknown, PatternResult::Failure) => {}
+                _ => *pending = result.clone(),
+            }
+        }
+    }
+}
+
+impl<E, P, S> Pattern for SequencePattern<P>
+where
+    S: Default,
+    P: Pattern<Event = E, State = S, T = (), W = Idx>,
+{
+    type State = SequencePatternState<S>;
+    type Event = E;
+    type T = ();
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        state: &mut Self::State,
+    ) {
+        state
+            .symbol_states
+            .resize_with(self.symbols.len(), Default::default);
+        state
+            .symbol_queues
+            .resize_with(self.symbols.len(), Default::default);
+
+        for ((symbol, symbol_state), symbol_queue) in self
+            .symbols
+            .iter()
+            .zip(state.symbol_states.iter_mut())
+            .zip(state.symbol_queues.iter_mut())
+        {
+            symbol.apply(start_idx, event, symbol_queue, symbol_state);
+        }
+
+        let mut visited = vec![false; self.nfa.states.len()];
+        loop {
+            let idx = state.next_idx;
+            // results of all symbols must be known for idx, `None` stands for unknown result
+            let mut matched = Vec::with_capacity(self.symbols.len());
+            for symbol_queue in state.symbol_queues.iter_mut() {
+                match symbol_queue.rewind_to(idx).head_option() {
+                    Some(IdxValue { start, .. }) if *start != idx => matched.push(Some(false)),
+                    Some(IdxValue { result, .. }) => matched.push(match result {
+                        PatternResult::Success(()) => Some(true),
+                        PatternResult::Failure => Some(false),
+                        PatternResult::Unknown => None,
+                    }),
+                    None => return,
+                }
+            }
+
+            // `active` are threads reached for sure, `possible` are also reached through unknowns
+            let active = self.nfa.step(&state.active, idx, &mut visited, |symbol| {
+                matched[symbol] == Some(true)
+            });
+            let possible = self.nfa.step(&state.possible, idx, &mut visited, |symbol| {
+                matched[symbol] != Some(false)
+            });
+
+            state.pending.push_back((PatternResult::Failure, false));
+            if let Some(start) = self.nfa.accepts(&active) {
+                state.mark(start, idx, PatternResult::Success(()));
+            } else if let Some(start) = self.nfa.accepts(&possible) {
+                state.mark(start, idx, PatternResult::Unknown);
+            }
+
+            // indices before the earliest running match are not changed anymore
+            let running = active
+                .iter()
+                .chain(possible.iter())
+                .filter(|(s, _)| !matches!(self.nfa.states[*s], NfaState::Match))
+                .map(|(_, start)| *start)
+                .min()
+                .unwrap_or(idx + 1);
+            while state.resolved < running {
+                let (result, starts_match) = state.pending.pop_front().expect("Illegal state");
+                let idx_value = IdxValue::new(state.resolved, state.resolved, result);
+                // adjacent matches are separate intervals
+                if starts_match {
+                    queue.enqueue_one(idx_value);
+                } else {
+                    queue.enqueue_joined(idx_value);
+                }
+                state.resolved += 1;
+            }
+
+            state.active = active;
+            state.possible = possible;
+            state.next_idx += 1;
+        }
+    }
+
+    type W = Idx;
+
+    fn width(&self) -> Self::W {
+        self.width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::patterns::{AssertPattern, FunctionPattern};
+
+    fn run<P: Pattern<T = ()>>(pattern: &P, events: &[P::Event], chunk: usize) -> Vec<bool> {
+        let mut state = P::State::default();
+        let mut queue = PQueue::default();
+        let mut start_idx = 0;
+        for chunk in events.chunks(chunk) {
+            pattern.apply(start_idx, chunk, &mut queue, &mut state);
+            start_idx += chunk.len() as Idx;
+        }
+        let mut results = vec![];
+        while let Some(IdxValue { start, end, result }) = queue.dequeue_option() {
+            for _ in start..=end {
+                results.push(result == PatternResult::Success(()));
+            }
+        }
+        results
+    }
+
+    fn symbol(name: char) -> (char, impl Pattern<Event = char, T = (), W = Idx>) {
+        let c = name.to_ascii_lowercase();
+        (
+            name,
+            AssertPattern::new(FunctionPattern::new(move |e: &char| *e == c)),
+        )
+    }
+
+    #[test]
+    fn parses_expressions() {
+        let expr = SequenceExpr::parse("A B* C{2,5}", &['A', 'B', 'C']).unwrap();
+        assert_eq!(
+            expr,
+            SequenceExpr::Concat(vec![
+                SequenceExpr::Symbol(0),
+                SequenceExpr::Repeat {
+                    inner: Box::new(SequenceExpr::Symbol(1)),
+                    min: 0,
+                    max: None,
+                },
+                SequenceExpr::Repeat {
+                    inner: Box::new(SequenceExpr::Symbol(2)),
+                    min: 2,
+                    max: Some(5),
+                },
+            ])
+        );
+        assert_eq!(SequenceExpr::parse("A D", &['A']).unwrap_err().position, 1);
+        assert!(SequenceExpr::parse("(A|B", &['A', 'B']).is_err());
+        assert!(SequenceExpr::parse("A{3,2}", &['A']).is_err());
+    }
+
+    #[test]
+    fn matches_sequences() {
+        let pattern =
+            SequencePattern::parse("A B* C{2}", vec![symbol('A'), symbol('B'), symbol('C')])
+                .unwrap();
+        let events: Vec<char> = "acabbccc".chars().collect();
+        let expected = vec![false, false, true, true, true, true, true, false];
+        assert_eq!(run(&pattern, &events, 3), expected);
+        assert_eq!(run(&pattern, &events, 1), expected);
+    }
+
+    #[test]
+    fn matches_alternations() {
+        let pattern =
+            SequencePattern::parse("(A | B)+ C", vec![symbol('A'), symbol('B'), symbol('C')])
+                .unwrap();
+        let events: Vec<char> = "cabcbc".chars().collect();
+        assert_eq!(
+            run(&pattern, &events, 2),
+            vec![false, true, true, true, true, true]
+        );
+    }
+
+    #[test]
+    fn returns_match_spans() {
+        let symbols: Vec<(char, BoxedSymbol<char>)> = vec![
+            (
+                'A',
+                Box::new(AssertPattern::new(FunctionPattern::new(|e: &char| {
+                    *e == 'a'
+                }))),
+            ),
+            (
+                'B',
+                Box::new(AssertPattern::new(FunctionPattern::new(|e: &char| {
+                    *e != 'a'
+                }))),
+            ),
+        ];
+        let pattern = SequencePattern::parse("A B+", symbols).unwrap();
+        assert_eq!(pattern.width(), Idx::MAX);
+        let mut state = Default::default();
+        let mut queue = PQueue::default();
+        let events: Vec<char> = "babbbaab".chars().collect();
+        for (i, chunk) in events.chunks(3).enumerate() {
+            pattern.apply(i as Idx * 3, chunk, &mut queue, &mut state);
+        }
+        let mut spans = vec![];
+        while let Some(IdxValue { start, end, result }) = queue.dequeue_option() {
+            spans.push((start, end, result == PatternResult::Success(())));
+        }
+        // the last match may still continue
+        assert_eq!(spans, vec![(0, 0, false), (1, 4, true), (5, 5, false)]);
+    }
+
+    #[test]
+    fn separates_adjacent_matches() {
+        let pattern = SequencePattern::parse("A B", vec![symbol('A'), symbol('B')]).unwrap();
+        let mut state = Default::default();
+        let mut queue = PQueue::default();
+        let events: Vec<char> = "ababb".chars().collect();
+        pattern.apply(0, &events, &mut queue, &mut state);
+        let mut spans = vec![];
+        while let Some(IdxValue { start, end, result }) = queue.dequeue_option() {
+            spans.push((start, end, result == PatternResult::Success(())));
+        }
+        assert_eq!(spans, vec![(0, 1, true), (2, 3, true), (4, 4, false)]);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let symbols = || vec![symbol('A').1];
+        assert!(SequencePattern::new(SequenceExpr::Symbol(1), symbols()).is_err());
+        let empty = SequenceExpr::Repeat {
+            inner: Box::new(SequenceExpr::Symbol(0)),
+            min: 0,
+            max: Some(2),
+        };
+        assert!(SequencePattern::new(empty, symbols()).is_err());
+        assert!(SequencePattern::new(SequenceExpr::Symbol(0), symbols()).is_ok());
+    }
+}
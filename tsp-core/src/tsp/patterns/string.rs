@@ -0,0 +1,168 @@
+use std::marker::PhantomData;
+
+use regex::Regex;
+
+use crate::tsp::patterns::common::NoState;
+use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
+
+/// Predicate applied to a string field of the event.
+#[derive(Debug, Clone)]
+pub enum StringMatcher {
+    Equals(String),
+    /// Both strings are compared in lower case, so the value may be given in any case.
+    EqualsIgnoreCase(String),
+    Contains(String),
+    StartsWith(String),
+    EndsWith(String),
+    Regex(Regex),
+}
+
+impl StringMatcher {
+    pub fn matches(&self, s: &str) -> bool {
+        match self {
+            StringMatcher::Equals(other) => s == other,
+            StringMatcher::EqualsIgnoreCase(other) => s
+                .chars()
+                .flat_map(char::to_lowercase)
+                .eq(other.chars().flat_map(char::to_lowercase)),
+            StringMatcher::Contains(other) => s.contains(other.as_str()),
+            StringMatcher::StartsWith(other) => s.starts_with(other.as_str()),
+            StringMatcher::EndsWith(other) => s.ends_with(other.as_str()),
+            StringMatcher::Regex(regex) => regex.is_match(s),
+        }
+    }
+}
+
+/// Returns `bool` for every event telling whether the string field extracted by `func`
+/// satisfies the matcher. Is meant to be used inside of `AssertPattern`.
+#[derive(Clone)]
+pub struct StringPattern<E, F, S>
+where
+    F: Fn(&E) -> S,
+    S: AsRef<str>,
+{
+    func: F,
+    matcher: StringMatcher,
+    phantom: PhantomData<E>,
+}
+
+impl<E, F, S> StringPattern<E, F, S>
+where
+    F: Fn(&E) -> S,
+    S: AsRef<str>,
+{
+    pub fn new(func: F, matcher: StringMatcher) -> Self {
+        StringPattern {
+            func,
+            matcher,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn equals(func: F, value: &str) -> Self {
+        StringPattern::new(func, StringMatcher::Equals(value.to_string()))
+    }
+
+    pub fn equals_ignore_case(func: F, value: &str) -> Self {
+        StringPattern::new(func, StringMatcher::EqualsIgnoreCase(value.to_string()))
+    }
+
+    pub fn contains(func: F, value: &str) -> Self {
+        StringPattern::new(func, StringMatcher::Contains(value.to_string()))
+    }
+
+    pub fn starts_with(func: F, value: &str) -> Self {
+        StringPattern::new(func, StringMatcher::StartsWith(value.to_string()))
+    }
+
+    pub fn ends_with(func: F, value: &str) -> Self {
+        StringPattern::new(func, StringMatcher::EndsWith(value.to_string()))
+    }
+
+    /// The regex is compiled once here and is shared by all partitions.
+    pub fn regex(func: F, regex: &str) -> Result<Self, regex::Error> {
+        Ok(StringPattern::new(
+            func,
+            StringMatcher::Regex(Regex::new(regex)?),
+        ))
+    }
+}
+
+impl<E, F, S> Pattern for StringPattern<E, F, S>
+where
+    F: Fn(&E) -> S,
+    S: AsRef<str>,
+{
+    type State = NoState;
+    type Event = E;
+    type T = bool;
+
+    fn apply(
+        &self,
+        start_idx: Idx,
+        event: &[Self::Event],
+        queue: &mut PQueue<Self::T>,
+        _state: &mut Self::State,
+    ) {
+        event.iter().enumerate().for_each(|(idx, e)| {
+            queue.enqueue_joined(IdxValue::new(
+                start_idx + idx as Idx,
+                start_idx + idx as Idx,
+                PatternResult::Success(self.matcher.matches((self.func)(e).as_ref())),
+            ));
+        })
+    }
+
+    type W = Idx;
+
+    fn width(&self) -> Self::W {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_strings() {
+        let equals = StringMatcher::Equals("Error".to_string());
+        assert!(equals.matches("Error"));
+        assert!(!equals.matches("error"));
+
+        let ignore_case = StringMatcher::EqualsIgnoreCase("ErRor".to_string());
+        assert!(ignore_case.matches("error"));
+        assert!(ignore_case.matches("ERROR"));
+        assert!(!ignore_case.matches("errors"));
+        assert!(StringMatcher::EqualsIgnoreCase("STRASSE Ä".to_string()).matches("strasse ä"));
+
+        let contains = StringMatcher::Contains("rr".to_string());
+        assert!(contains.matches("error"));
+        assert!(!contains.matches("warn"));
+
+        let starts_with = StringMatcher::StartsWith("err".to_string());
+        assert!(starts_with.matches("error"));
+        assert!(!starts_with.matches("an error"));
+
+        let ends_with = StringMatcher::EndsWith("or".to_string());
+        assert!(ends_with.matches("error"));
+        assert!(!ends_with.matches("errors"));
+
+        let regex = StringMatcher::Regex(Regex::new("^e.+r$").unwrap());
+        assert!(regex.matches("error"));
+        assert!(!regex.matches("warn"));
+    }
+
+    #[test]
+    fn applies_matcher_to_field() {
+        let pattern = StringPattern::equals_ignore_case(|e: &(u64, &str)| e.1, "Disk Full");
+        let mut queue = PQueue::default();
+        let events = [(0, "disk full"), (1, "DISK FULL"), (2, "disk ok")];
+        pattern.apply(0, &events, &mut queue, &mut NoState);
+        let mut results = vec![];
+        while let Some(IdxValue { start, end, result }) = queue.dequeue_option() {
+            results.push((start, end, result == PatternResult::Success(true)));
+        }
+        assert_eq!(results, vec![(0, 1, true), (2, 2, false)]);
+    }
+}
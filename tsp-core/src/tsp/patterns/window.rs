@@ -1,3 +1,7 @@
+use std::cmp::{max, min};
+
+use serde::{Deserialize, Serialize};
+
 use crate::tsp::patterns::pattern::{Idx, IdxValue, PQueue, Pattern, PatternResult};
 
 #[derive(Debug, Copy, Clone)]
@@ -24,12 +28,12 @@ where
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct WindowPatternState<S: Default> {
     inner_state: S,
     inner_queue: PQueue<()>,
-    last_end: Idx,
-    last_success: bool,
+    last_failure: Option<Idx>,
+    last_unknown: Option<Idx>,
 }
 
 impl<E, P, InnerState> Pattern for WindowPattern<P>
@@ -56,44 +60,37 @@ where
             &mut state.inner_state,
         );
 
-        while let Some(IdxValue {
-            start: _,
-            end,
-            result,
-        }) = state.inner_queue.dequeue_option()
-        {
-            assert!(state.last_end < end);
+        let size = self.window.size as Idx;
+        while let Some(IdxValue { start, end, result }) = state.inner_queue.dequeue_option() {
             match result {
-                PatternResult::Failure => {
-                    queue.enqueue_joined(IdxValue::new(
-                        state.last_end + 1,
-                        end,
-                        PatternResult::Failure,
-                    ));
-                    state.last_end = end;
-                    state.last_success = false;
-                }
-                PatternResult::Success(()) => {
-                    if state.last_success {
-                        queue.enqueue_joined(IdxValue::new(
-                            state.last_end + 1,
-                            end,
-                            PatternResult::Success(()),
-                        ));
-                        state.last_end = end;
-                        state.last_success = true;
-                    } else {
-                        let new_start = state.last_end + self.window.size as u64;
-                        if new_start <= end {
-                            queue.enqueue_joined(IdxValue::new(
-                                new_start,
-                                end,
-                                PatternResult::Success(()),
-                            ));
-                            state.last_end = end;
-                            state.last_success = true;
-                        }
+                PatternResult::Failure => state.last_failure = Some(end),
+                PatternResult::Unknown => state.last_unknown = Some(end),
+                PatternResult::Success(()) => {}
+            }
+
+            // window ending at idx fails if it contains a failure, otherwise it is unknown if it
+            // contains unknown result or starts before the beginning of partition.
+            let failure_until = state.last_failure.map(|f| f + size - 1);
+            let unknown_until = max(
+                state.last_unknown.map(|u| u + size - 1),
+                (size - 1).checked_sub(1),
+            );
+
+            let mut idx = start;
+            for (until, result) in [
+                (failure_until, PatternResult::Failure),
+                (unknown_until, PatternResult::Unknown),
+                (Some(end), PatternResult::Success(())),
+            ]
+            .iter()
+            {
+                match until {
+                    Some(until) if *until >= idx && idx <= end => {
+                        let piece_end = min(*until, end);
+                        queue.enqueue_joined(IdxValue::new(idx, piece_end, result.clone()));
+                        idx = piece_end + 1;
                     }
+                    _ => {}
                 }
             }
         }
@@ -102,6 +99,69 @@ where
     type W = Idx;
 
     fn width(&self) -> Self::W {
-        (self.window.size - 1) as u64 + self.inner.width()
+        ((self.window.size - 1) as u64).saturating_add(self.inner.width())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::patterns::{
+        AssertPattern, FieldPattern, FunctionPattern, MissingPolicy, Nullable,
+    };
+
+    #[test]
+    fn unknown_until_window_is_filled() {
+        let pattern = WindowPattern::new(
+            AssertPattern::new(FunctionPattern::new(|e: &u32| *e > 0)),
+            3,
+        );
+        let mut state = Default::default();
+        let mut queue = PQueue::default();
+        pattern.apply(0, &[1, 1, 1, 0, 1, 1, 1], &mut queue, &mut state);
+
+        let results: Vec<(Idx, Idx, PatternResult<()>)> =
+            std::iter::from_fn(|| queue.dequeue_option())
+                .map(|IdxValue { start, end, result }| (start, end, result))
+                .collect();
+        assert_eq!(
+            results,
+            vec![
+                (0, 1, PatternResult::Unknown),
+                (2, 2, PatternResult::Success(())),
+                (3, 5, PatternResult::Failure),
+                (6, 6, PatternResult::Success(())),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_values_are_unknown() {
+        // NaN compared as a number would fail every window containing it
+        let pattern = WindowPattern::new(
+            AssertPattern::new(FieldPattern::new(
+                FunctionPattern::new(|e: &f64| e.into_value().map(|v| v > 0.0)),
+                MissingPolicy::Skip,
+            )),
+            3,
+        );
+        let mut state = Default::default();
+        let mut queue = PQueue::default();
+        let events = [1.0, 1.0, 1.0, f64::NAN, 1.0, 1.0, 1.0];
+        pattern.apply(0, &events, &mut queue, &mut state);
+
+        let results: Vec<(Idx, Idx, PatternResult<()>)> =
+            std::iter::from_fn(|| queue.dequeue_option())
+                .map(|IdxValue { start, end, result }| (start, end, result))
+                .collect();
+        assert_eq!(
+            results,
+            vec![
+                (0, 1, PatternResult::Unknown),
+                (2, 2, PatternResult::Success(())),
+                (3, 5, PatternResult::Unknown),
+                (6, 6, PatternResult::Success(())),
+            ]
+        );
     }
 }
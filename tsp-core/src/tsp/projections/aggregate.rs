@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::{Add, Sub};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::pattern::Idx;
+use crate::tsp::projections::projection::{
+    check_window, NoProjectionState, Projection, ProjectionError, ProjectionResult, ValueProjection,
+};
+
+/// Keeps prefix sums instead of raw events, so sum of any window is a difference of two of them.
+/// Prefix sums start from zero after every extracted window, so they don't grow with the length
+/// of the stream.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrefixSumState<T> {
+    /// `sums[i]` is the sum of all values from `first_idx` up to `first_idx + i` inclusive.
+    sums: VecDeque<T>,
+    first_idx: Idx,
+}
+
+impl<T> Default for PrefixSumState<T> {
+    fn default() -> Self {
+        PrefixSumState {
+            sums: VecDeque::new(),
+            first_idx: 0,
+        }
+    }
+}
+
+impl<T> PrefixSumState<T>
+where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T>,
+{
+    pub(crate) fn push(&mut self, value: T) {
+        let total = self.sums.back().copied().unwrap_or_default();
+        self.sums.push_back(total + value);
+    }
+
+    pub(crate) fn discard(&mut self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        self.sum(start, end).map(|_| ())
+    }
+
+    // returns sum of values from `start` to `end` inclusive and forgets everything before `end`
+    pub(crate) fn sum(&mut self, start: Idx, end: Idx) -> ProjectionResult<T> {
+        let sum = self.peek_sum(start, end)?;
+        let removed = self.sums[(end - self.first_idx) as usize];
+        self.sums.drain(..=(end - self.first_idx) as usize);
+        self.sums.iter_mut().for_each(|s| *s = *s - removed);
+        self.first_idx = end + 1;
+        Ok(sum)
+    }
+
+    // returns sum of values from `start` to `end` inclusive without changing the state
+    pub(crate) fn peek_sum(&self, start: Idx, end: Idx) -> ProjectionResult<T> {
+        check_window(
+            start,
+            end,
+            self.first_idx,
+            self.first_idx + self.sums.len() as Idx,
+        )?;
+        let before_start = match start - self.first_idx {
+            0 => T::default(),
+            n => self.sums[n as usize - 1],
+        };
+        Ok(self.sums[(end - self.first_idx) as usize] - before_start)
+    }
+}
+
+pub struct SumProjection<E, F: Fn(&E) -> T, T>(F, PhantomData<E>, PhantomData<T>);
+
+impl<E, F, T> SumProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+{
+    pub fn new(field0: F) -> Self {
+        SumProjection(field0, PhantomData, PhantomData)
+    }
+}
+
+impl<E, F, T> Projection for SumProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+    T: Copy + Default + Add<Output = T> + Sub<Output = T>,
+{
+    type Event = E;
+    type State = PrefixSumState<T>;
+    type T = T;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        events.iter().for_each(|e| state.push(self.0(e)))
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<T> {
+        state.sum(start, end)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        state.discard(start, end)
+    }
+
+    fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<T> {
+        state.peek_sum(start, end)
+    }
+}
+
+impl<E, F, T, V> ValueProjection<V> for SumProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+    T: Copy + Default + Add<Output = T> + Sub<Output = T>,
+{
+}
+
+/// Lossy conversion of numeric fields to `f64`, unlike `Into<f64>` is implemented for 64 bit
+/// integers too.
+pub trait ToF64: Copy {
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! to_f64 {
+    ($($t:ty),*) => {
+        $(
+            impl ToF64 for $t {
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+to_f64!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+pub struct AvgProjection<E, F: Fn(&E) -> T, T>(F, PhantomData<E>, PhantomData<T>);
+
+impl<E, F, T> AvgProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+{
+    pub fn new(field0: F) -> Self {
+        AvgProjection(field0, PhantomData, PhantomData)
+    }
+}
+
+impl<E, F, T> Projection for AvgProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+    T: Default + Add<Output = T> + Sub<Output = T> + ToF64,
+{
+    type Event = E;
+    type State = PrefixSumState<T>;
+    type T = f64;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        events.iter().for_each(|e| state.push(self.0(e)))
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<f64> {
+        Ok(state.sum(start, end)?.to_f64() / (end - start + 1) as f64)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        state.discard(start, end)
+    }
+
+    fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<f64> {
+        Ok(state.peek_sum(start, end)?.to_f64() / (end - start + 1) as f64)
+    }
+}
+
+impl<E, F, T, V> ValueProjection<V> for AvgProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+    T: Default + Add<Output = T> + Sub<Output = T> + ToF64,
+{
+}
+
+/// Number of events in the window, doesn't keep anything in state.
+pub struct CountProjection<E>(PhantomData<E>);
+
+impl<E> CountProjection<E> {
+    pub fn new() -> Self {
+        CountProjection(PhantomData)
+    }
+}
+
+impl<E> Default for CountProjection<E> {
+    fn default() -> Self {
+        CountProjection::new()
+    }
+}
+
+impl<E> Projection for CountProjection<E> {
+    type Event = E;
+    type State = NoProjectionState;
+    type T = u64;
+
+    fn update(&self, _start_idx: Idx, _events: &[Self::Event], _state: &mut Self::State) {}
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<u64> {
+        self.peek(state, start, end)
+    }
+
+    fn peek(&self, _state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<u64> {
+        if start > end {
+            return Err(ProjectionError::InvalidWindow { start, end });
+        }
+        Ok(end - start + 1)
+    }
+}
+
+impl<E, V> ValueProjection<V> for CountProjection<E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TE(u64);
+
+    fn events() -> Vec<TE> {
+        vec![TE(20), TE(13), TE(34), TE(567), TE(1)]
+    }
+
+    #[test]
+    fn sum_projection() {
+        let sum_projection = SumProjection::new(|e: &TE| e.0);
+        let mut state = PrefixSumState::default();
+        sum_projection.update(0, &events()[..3], &mut state);
+        sum_projection.update(3, &events()[3..], &mut state);
+
+        assert_eq!(sum_projection.extract(&mut state, 1, 2), Ok(47));
+        assert_eq!(state.first_idx, 3);
+        assert_eq!(state.sums.len(), 2);
+        assert_eq!(sum_projection.extract(&mut state, 4, 4), Ok(1));
+        assert!(state.sums.is_empty());
+    }
+
+    #[test]
+    fn long_stream_sum() {
+        let sum_projection = SumProjection::new(|e: &u32| *e);
+        let mut state = PrefixSumState::default();
+        for i in 0..1000 {
+            sum_projection.update(i * 2, &[1_000_000_000, 1_000_000_000], &mut state);
+            assert_eq!(
+                sum_projection.extract(&mut state, i * 2, i * 2 + 1),
+                Ok(2_000_000_000)
+            );
+        }
+
+        // small values after a huge one are not lost
+        let sum_projection = SumProjection::new(|e: &f64| *e);
+        let mut state = PrefixSumState::default();
+        sum_projection.update(0, &[1e17], &mut state);
+        assert_eq!(sum_projection.extract(&mut state, 0, 0), Ok(1e17));
+        sum_projection.update(1, &[1.0, 1.0, 1.0], &mut state);
+        assert_eq!(sum_projection.extract(&mut state, 1, 3), Ok(3.0));
+    }
+
+    #[test]
+    fn avg_projection() {
+        let avg_projection = AvgProjection::new(|e: &TE| e.0);
+        let mut state = PrefixSumState::default();
+        avg_projection.update(0, &events(), &mut state);
+
+        assert_eq!(avg_projection.extract(&mut state, 0, 1), Ok(16.5));
+        assert_eq!(avg_projection.extract(&mut state, 3, 4), Ok(284.0));
+    }
+
+    #[test]
+    fn count_projection() {
+        let count_projection = CountProjection::new();
+        let mut state = NoProjectionState;
+        count_projection.update(0, &events(), &mut state);
+        assert_eq!(count_projection.extract(&mut state, 1, 3), Ok(3));
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::pattern::Idx;
+use crate::tsp::projections::projection::{
+    check_window, Projection, ProjectionError, ProjectionResult, ValueProjection,
+};
+
+/// Which values of the window `CollectProjection` returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollectMode {
+    All,
+    FirstK(usize),
+    LastK(usize),
+    /// Uniform random sample of `k` values, the seed makes results reproducible.
+    Reservoir {
+        k: usize,
+        seed: u64,
+    },
+}
+
+/// Returns values of the window selected according to `CollectMode`. At most `memory_cap`
+/// values are kept in state. When it overflows, the oldest values are dropped, except for the
+/// first `k` values after the last window which `FirstK` needs. If values needed for the window
+/// were dropped,
+/// `ProjectionError::CapExceeded` is returned. Bounds of the window are known only in `extract`,
+/// so the reservoir is sampled there from the kept values.
+pub struct CollectProjection<E, F: Fn(&E) -> T, T> {
+    func: F,
+    mode: CollectMode,
+    memory_cap: usize,
+    phantom: PhantomData<(E, T)>,
+}
+
+impl<E, F, T> CollectProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+{
+    pub fn new(func: F, mode: CollectMode, memory_cap: usize) -> Self {
+        assert!(memory_cap > 0);
+        CollectProjection {
+            func,
+            mode,
+            memory_cap,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CollectProjectionState<T> {
+    /// The first values after the last window which were dropped from `queue` on overflow, they
+    /// are kept only for `FirstK`.
+    head: VecDeque<T>,
+    queue: VecDeque<T>,
+    /// Index of the first value in `queue`.
+    first_idx: Idx,
+    /// Windows before `extracted` were already extracted or discarded, `head` starts there.
+    extracted: Idx,
+    next_idx: Idx,
+    random: u64,
+}
+
+impl<T> Default for CollectProjectionState<T> {
+    fn default() -> Self {
+        CollectProjectionState {
+            head: VecDeque::new(),
+            queue: VecDeque::new(),
+            first_idx: 0,
+            extracted: 0,
+            next_idx: 0,
+            random: 0,
+        }
+    }
+}
+
+// xorshift64*, good enough for sampling
+fn next_random(random: &mut u64, seed: u64) -> u64 {
+    if *random == 0 {
+        *random = seed | 1;
+    }
+    *random ^= *random >> 12;
+    *random ^= *random << 25;
+    *random ^= *random >> 27;
+    random.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+impl<T> CollectProjectionState<T> {
+    // `first_k` is the number of the first values of the window which must not be dropped on
+    // overflow
+    fn push(&mut self, value: T, memory_cap: usize, first_k: usize) {
+        if self.head.len() + self.queue.len() == memory_cap {
+            // values at the front of `queue` may be the first ones of the next window
+            while self.head.len() < first_k
+                && self.first_idx == self.extracted + self.head.len() as Idx
+            {
+                match self.queue.pop_front() {
+                    Some(first) => {
+                        self.head.push_back(first);
+                        self.first_idx += 1;
+                    }
+                    None => break,
+                }
+            }
+            if self.queue.pop_front().is_some() {
+                self.first_idx += 1;
+            }
+        }
+        if self.head.len() + self.queue.len() < memory_cap {
+            if self.queue.is_empty() {
+                self.first_idx = self.next_idx;
+            }
+            self.queue.push_back(value);
+        }
+        self.next_idx += 1;
+    }
+
+    // checks the window and that the values from `from` to `to` are kept
+    fn check(&self, start: Idx, end: Idx, from: Idx, to: Idx, cap: usize) -> ProjectionResult<()> {
+        check_window(start, end, self.extracted, self.next_idx)?;
+        let head_end = self.extracted + self.head.len() as Idx;
+        let queue_end = self.first_idx + self.queue.len() as Idx;
+        let kept = to < head_end
+            || (from >= self.first_idx || head_end == self.first_idx) && to < queue_end;
+        if from > to || kept {
+            Ok(())
+        } else {
+            Err(ProjectionError::CapExceeded { start, end, cap })
+        }
+    }
+
+    // forgets the values up to `end`
+    fn forget(&mut self, end: Idx) {
+        let dropped = (end + 1).saturating_sub(self.extracted) as usize;
+        self.head.drain(..dropped.min(self.head.len()));
+        let dropped = (end + 1).saturating_sub(self.first_idx) as usize;
+        self.queue.drain(..dropped.min(self.queue.len()));
+        self.first_idx = self.first_idx.max(end + 1);
+        self.extracted = self.extracted.max(end + 1);
+    }
+
+    // values from `from` to `to`, which must be checked before
+    fn values(&self, from: Idx, to: Idx) -> impl Iterator<Item = &T> {
+        (from..=to).map(move |idx| match idx.checked_sub(self.first_idx) {
+            Some(i) => &self.queue[i as usize],
+            None => &self.head[(idx - self.extracted) as usize],
+        })
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: Clone> Projection for CollectProjection<E, F, T> {
+    type Event = E;
+    type State = CollectProjectionState<T>;
+    type T = Vec<T>;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        let first_k = match self.mode {
+            CollectMode::FirstK(k) => k,
+            _ => 0,
+        };
+        for e in events {
+            state.push((self.func)(e), self.memory_cap, first_k);
+        }
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<Vec<T>> {
+        // values of the window which are needed for the result
+        let (from, to) = match self.mode {
+            CollectMode::FirstK(0) | CollectMode::LastK(0) => (end + 1, end),
+            CollectMode::FirstK(k) => (start, end.min(start + k as Idx - 1)),
+            CollectMode::LastK(k) => (start.max((end + 1).saturating_sub(k as Idx)), end),
+            CollectMode::All | CollectMode::Reservoir { .. } => (start, end),
+        };
+        if let Err(e) = state.check(start, end, from, to, self.memory_cap) {
+            // the window is over anyway, so the values are forgotten to keep the state bounded
+            if let ProjectionError::CapExceeded { .. } = e {
+                state.forget(end);
+            }
+            return Err(e);
+        }
+        if from > to {
+            state.forget(end);
+            return Ok(vec![]);
+        }
+
+        let result = match self.mode {
+            CollectMode::Reservoir { k, seed } => {
+                let mut random = state.random;
+                let mut sample: Vec<T> = Vec::with_capacity(k);
+                for (i, value) in state.values(from, to).enumerate() {
+                    if i < k {
+                        sample.push(value.clone());
+                    } else {
+                        let j = (next_random(&mut random, seed) % (i as u64 + 1)) as usize;
+                        if j < k {
+                            sample[j] = value.clone();
+                        }
+                    }
+                }
+                state.random = random;
+                sample
+            }
+            _ => state.values(from, to).cloned().collect(),
+        };
+        state.forget(end);
+        Ok(result)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        check_window(start, end, state.extracted, state.next_idx)?;
+        state.forget(end);
+        Ok(())
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: Clone, V> ValueProjection<V> for CollectProjection<E, F, T> {}
+
+/// Counts values of the window in buckets. `bounds` must be sorted, the result has
+/// `bounds.len() + 1` counts: `counts[i]` is the number of values in `[bounds[i - 1], bounds[i])`,
+/// the first and the last buckets are open. Only the bucket number is kept for every event, at
+/// most `memory_cap` of them. If the window is longer, `ProjectionError::CapExceeded` is
+/// returned.
+pub struct HistogramProjection<E, F: Fn(&E) -> T, T> {
+    func: F,
+    bounds: Vec<T>,
+    memory_cap: usize,
+    phantom: PhantomData<E>,
+}
+
+impl<E, F, T> HistogramProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+    T: PartialOrd,
+{
+    pub fn new(func: F, bounds: Vec<T>, memory_cap: usize) -> Self {
+        assert!(bounds.windows(2).all(|w| w[0] < w[1]));
+        assert!(memory_cap > 0);
+        HistogramProjection {
+            func,
+            bounds,
+            memory_cap,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: PartialOrd> Projection for HistogramProjection<E, F, T> {
+    type Event = E;
+    type State = CollectProjectionState<u32>;
+    type T = Vec<u64>;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        for e in events {
+            let value = (self.func)(e);
+            let bucket = self.bounds.iter().take_while(|b| **b <= value).count() as u32;
+            state.push(bucket, self.memory_cap, 0);
+        }
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<Vec<u64>> {
+        if let Err(e) = state.check(start, end, start, end, self.memory_cap) {
+            if let ProjectionError::CapExceeded { .. } = e {
+                state.forget(end);
+            }
+            return Err(e);
+        }
+        let mut counts = vec![0; self.bounds.len() + 1];
+        for bucket in state.values(start, end) {
+            counts[*bucket as usize] += 1;
+        }
+        state.forget(end);
+        Ok(counts)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        check_window(start, end, state.extracted, state.next_idx)?;
+        state.forget(end);
+        Ok(())
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: PartialOrd, V> ValueProjection<V> for HistogramProjection<E, F, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(
+        mode: CollectMode,
+        memory_cap: usize,
+        start: Idx,
+        end: Idx,
+    ) -> ProjectionResult<Vec<u32>> {
+        let projection = CollectProjection::new(|e: &u32| *e, mode, memory_cap);
+        let mut state = CollectProjectionState::default();
+        projection.update(0, &[0, 1, 2, 3, 4, 5, 6, 7], &mut state);
+        projection.extract(&mut state, start, end)
+    }
+
+    #[test]
+    fn collect_projection() {
+        assert_eq!(collect(CollectMode::All, 100, 2, 4), Ok(vec![2, 3, 4]));
+        assert_eq!(collect(CollectMode::FirstK(2), 100, 2, 6), Ok(vec![2, 3]));
+        assert_eq!(collect(CollectMode::LastK(2), 100, 2, 6), Ok(vec![5, 6]));
+        let sample = collect(CollectMode::Reservoir { k: 3, seed: 42 }, 100, 0, 7).unwrap();
+        assert_eq!(sample.len(), 3);
+        assert!(sample.iter().all(|v| *v < 8));
+    }
+
+    #[test]
+    fn memory_cap_per_mode() {
+        let exceeded = Err(ProjectionError::CapExceeded {
+            start: 2,
+            end: 6,
+            cap: 3,
+        });
+        // the first values are kept for `FirstK`, the last ones for other modes
+        assert_eq!(collect(CollectMode::FirstK(2), 3, 0, 6), Ok(vec![0, 1]));
+        assert_eq!(collect(CollectMode::FirstK(2), 3, 2, 6), exceeded);
+        assert_eq!(collect(CollectMode::LastK(2), 3, 2, 6), Ok(vec![5, 6]));
+        assert_eq!(collect(CollectMode::All, 3, 2, 6), exceeded);
+        assert_eq!(
+            collect(CollectMode::Reservoir { k: 2, seed: 1 }, 3, 2, 6),
+            exceeded
+        );
+
+        // the state recovers after the window which didn't fit
+        let projection = CollectProjection::new(|e: &u32| *e, CollectMode::FirstK(2), 3);
+        let mut state = CollectProjectionState::default();
+        projection.update(0, &[0, 1, 2, 3, 4], &mut state);
+        assert_eq!(projection.extract(&mut state, 0, 1), Ok(vec![0, 1]));
+        assert!(projection.extract(&mut state, 2, 4).is_err());
+        projection.update(5, &[5, 6, 7], &mut state);
+        assert_eq!(projection.extract(&mut state, 5, 7), Ok(vec![5, 6]));
+    }
+
+    #[test]
+    fn first_values_after_discard() {
+        let projection = CollectProjection::new(|e: &u32| *e, CollectMode::FirstK(2), 50);
+        let mut state = CollectProjectionState::default();
+        let events: Vec<u32> = (0..100).collect();
+        projection.update(0, &events, &mut state);
+        assert_eq!(projection.discard(&mut state, 0, 79), Ok(()));
+        assert_eq!(projection.extract(&mut state, 80, 99), Ok(vec![80, 81]));
+    }
+
+    #[test]
+    fn histogram_projection() {
+        let projection = HistogramProjection::new(|e: &f64| *e, vec![10.0, 20.0], 5);
+        let mut state = CollectProjectionState::default();
+        projection.update(0, &[1.0, 10.0, 15.0, 25.0, 30.0, 5.0], &mut state);
+        assert!(projection.extract(&mut state, 0, 4).is_err());
+        assert_eq!(projection.extract(&mut state, 5, 5), Ok(vec![1, 0, 0]));
+        projection.update(6, &[1.0, 10.0, 15.0, 25.0, 30.0], &mut state);
+        assert_eq!(projection.extract(&mut state, 6, 10), Ok(vec![1, 2, 2]));
+    }
+}
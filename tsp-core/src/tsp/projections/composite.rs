@@ -0,0 +1,311 @@
+use std::any::Any;
+
+use crate::tsp::patterns::pattern::Idx;
+use crate::tsp::projections::projection::{
+    Projection, ProjectionError, ProjectionResult, ValueProjection,
+};
+
+// Tuple of projections is a projection returning tuple of their results. All of them are
+// updated with the same chunks and extracted with the same indices.
+macro_rules! tuple_projection {
+    ( $( $p:ident . $i:tt ),+ ) => {
+        impl<E, $( $p ),+> Projection for ( $( $p, )+ )
+        where
+            $( $p: Projection<Event = E>, )+
+        {
+            type Event = E;
+            type State = ( $( $p::State, )+ );
+            type T = ( $( $p::T, )+ );
+
+            fn update(&self, start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+                $( self.$i.update(start_idx, events, &mut state.$i); )+
+            }
+
+            fn extract(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<Self::T> {
+                Ok(( $( self.$i.extract(&mut state.$i, start, end)?, )+ ))
+            }
+
+            fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+                $( self.$i.discard(&mut state.$i, start, end)?; )+
+                Ok(())
+            }
+
+            fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<Self::T> {
+                Ok(( $( self.$i.peek(&state.$i, start, end)?, )+ ))
+            }
+        }
+
+        impl<E, V, $( $p ),+> ValueProjection<V> for ( $( $p, )+ )
+        where
+            $( $p: ValueProjection<V, Event = E>, )+
+        {
+            fn extract_with_value(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+                value: &V,
+            ) -> ProjectionResult<Self::T> {
+                Ok(( $( self.$i.extract_with_value(&mut state.$i, start, end, value)?, )+ ))
+            }
+        }
+    };
+}
+
+tuple_projection!(P0.0);
+tuple_projection!(P0.0, P1.1);
+tuple_projection!(P0.0, P1.1, P2.2);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6, P7.7);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6, P7.7, P8.8);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6, P7.7, P8.8, P9.9);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6, P7.7, P8.8, P9.9, P10.10);
+tuple_projection!(P0.0, P1.1, P2.2, P3.3, P4.4, P5.5, P6.6, P7.7, P8.8, P9.9, P10.10, P11.11);
+
+/// Projection with erased types of state and result, used for the fields of `RecordProjection`.
+/// `R` is the type of the rule's results passed to `extract_with_value_any`.
+pub trait DynProjection<E, V, R = ()>: Send + Sync {
+    fn new_state(&self) -> Box<dyn Any + Send>;
+    fn update_any(&self, start_idx: Idx, events: &[E], state: &mut dyn Any);
+    fn extract_any(&self, state: &mut dyn Any, start: Idx, end: Idx) -> ProjectionResult<V>;
+    fn discard_any(&self, state: &mut dyn Any, start: Idx, end: Idx) -> ProjectionResult<()>;
+    fn peek_any(&self, state: &dyn Any, start: Idx, end: Idx) -> ProjectionResult<V>;
+    fn extract_with_value_any(
+        &self,
+        state: &mut dyn Any,
+        start: Idx,
+        end: Idx,
+        value: &R,
+    ) -> ProjectionResult<V>;
+}
+
+impl<P, V, R> DynProjection<P::Event, V, R> for P
+where
+    P: ValueProjection<R> + Send + Sync,
+    P::State: Send + 'static,
+    P::T: Into<V>,
+{
+    fn new_state(&self) -> Box<dyn Any + Send> {
+        Box::new(P::State::default())
+    }
+
+    fn update_any(&self, start_idx: Idx, events: &[P::Event], state: &mut dyn Any) {
+        let state = state.downcast_mut().expect("Illegal state");
+        self.update(start_idx, events, state)
+    }
+
+    fn extract_any(&self, state: &mut dyn Any, start: Idx, end: Idx) -> ProjectionResult<V> {
+        let state = state.downcast_mut().expect("Illegal state");
+        self.extract(state, start, end).map(Into::into)
+    }
+
+    fn discard_any(&self, state: &mut dyn Any, start: Idx, end: Idx) -> ProjectionResult<()> {
+        let state = state.downcast_mut().expect("Illegal state");
+        self.discard(state, start, end)
+    }
+
+    fn peek_any(&self, state: &dyn Any, start: Idx, end: Idx) -> ProjectionResult<V> {
+        let state = state.downcast_ref().expect("Illegal state");
+        self.peek(state, start, end).map(Into::into)
+    }
+
+    fn extract_with_value_any(
+        &self,
+        state: &mut dyn Any,
+        start: Idx,
+        end: Idx,
+        value: &R,
+    ) -> ProjectionResult<V> {
+        let state = state.downcast_mut().expect("Illegal state");
+        self.extract_with_value(state, start, end, value)
+            .map(Into::into)
+    }
+}
+
+pub type BoxedProjection<E, V, R = ()> = Box<dyn DynProjection<E, V, R>>;
+
+/// Named fields with values of the common type `V`, e.g. an enum of all possible values.
+pub type Record<V> = Vec<(String, V)>;
+
+/// Projection with the set of fields defined at runtime, every field is computed by its own
+/// projection. `R` is the type of the rule's results, it's needed only for
+/// `PatternValueProjection` fields.
+pub struct RecordProjection<E, V, R = ()> {
+    fields: Vec<(String, BoxedProjection<E, V, R>)>,
+}
+
+impl<E, V, R> RecordProjection<E, V, R> {
+    pub fn new() -> Self {
+        RecordProjection { fields: vec![] }
+    }
+
+    pub fn field<P>(mut self, name: &str, projection: P) -> Self
+    where
+        P: DynProjection<E, V, R> + 'static,
+    {
+        self.fields.push((name.to_string(), Box::new(projection)));
+        self
+    }
+
+    fn init_state(&self, state: &mut RecordProjectionState) {
+        if state.fields.is_empty() {
+            state.fields = self.fields.iter().map(|(_, p)| p.new_state()).collect();
+        }
+    }
+}
+
+impl<E, V, R> Default for RecordProjection<E, V, R> {
+    fn default() -> Self {
+        RecordProjection::new()
+    }
+}
+
+/// State of `RecordProjection`. Types of the field states are erased, so unlike the other
+/// projection states it's not serializable.
+#[derive(Default)]
+pub struct RecordProjectionState {
+    fields: Vec<Box<dyn Any + Send>>,
+}
+
+impl<E, V: Clone, R> Projection for RecordProjection<E, V, R> {
+    type Event = E;
+    type State = RecordProjectionState;
+    type T = Record<V>;
+
+    fn update(&self, start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        self.init_state(state);
+        for ((_, projection), field_state) in self.fields.iter().zip(state.fields.iter_mut()) {
+            projection.update_any(start_idx, events, field_state.as_mut());
+        }
+    }
+
+    fn extract(
+        &self,
+        state: &mut Self::State,
+        start: Idx,
+        end: Idx,
+    ) -> ProjectionResult<Record<V>> {
+        self.init_state(state);
+        self.fields
+            .iter()
+            .zip(state.fields.iter_mut())
+            .map(|((name, projection), field_state)| {
+                Ok((
+                    name.clone(),
+                    projection.extract_any(field_state.as_mut(), start, end)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        self.init_state(state);
+        for ((_, projection), field_state) in self.fields.iter().zip(state.fields.iter_mut()) {
+            projection.discard_any(field_state.as_mut(), start, end)?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<Record<V>> {
+        // the state is initialized by the first update
+        if state.fields.len() != self.fields.len() {
+            return Err(ProjectionError::NotUpdated {
+                start,
+                end,
+                next_idx: 0,
+            });
+        }
+        self.fields
+            .iter()
+            .zip(state.fields.iter())
+            .map(|((name, projection), field_state)| {
+                Ok((
+                    name.clone(),
+                    projection.peek_any(field_state.as_ref(), start, end)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+impl<E, V: Clone, R> ValueProjection<R> for RecordProjection<E, V, R> {
+    fn extract_with_value(
+        &self,
+        state: &mut Self::State,
+        start: Idx,
+        end: Idx,
+        value: &R,
+    ) -> ProjectionResult<Record<V>> {
+        self.init_state(state);
+        self.fields
+            .iter()
+            .zip(state.fields.iter_mut())
+            .map(|((name, projection), field_state)| {
+                Ok((
+                    name.clone(),
+                    projection.extract_with_value_any(field_state.as_mut(), start, end, value)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::projections::{CountProjection, FirstProjection, LastProjection};
+
+    struct TE(u64, u32);
+
+    #[test]
+    fn tuple_projection() {
+        let projection = (
+            FirstProjection::new(|e: &TE| e.0),
+            LastProjection::new(|e: &TE| e.0),
+            CountProjection::new(),
+        );
+        let mut state = Default::default();
+        projection.update(0, &[TE(10, 1), TE(11, 2), TE(12, 3)], &mut state);
+        assert_eq!(projection.extract(&mut state, 1, 2), Ok((11, 12, 2)));
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Value {
+        Ts(u64),
+        Count(u64),
+    }
+
+    #[test]
+    fn record_projection() {
+        let projection = RecordProjection::<_, _, ()>::new()
+            .field("start", FirstProjection::new(|e: &TE| Value::Ts(e.0)))
+            .field(
+                "count",
+                LastProjection::new(|e: &TE| Value::Count(e.1 as u64)),
+            );
+        let mut state = Default::default();
+        projection.update(0, &[TE(10, 1), TE(11, 2), TE(12, 3)], &mut state);
+        assert_eq!(
+            projection.peek(&state, 0, 2),
+            Ok(vec![
+                ("start".to_string(), Value::Ts(10)),
+                ("count".to_string(), Value::Count(3))
+            ])
+        );
+        assert_eq!(
+            projection.extract(&mut state, 0, 1),
+            Ok(vec![
+                ("start".to_string(), Value::Ts(10)),
+                ("count".to_string(), Value::Count(2))
+            ])
+        );
+    }
+}
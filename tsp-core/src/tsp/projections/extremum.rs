@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::pattern::Idx;
+use crate::tsp::projections::projection::{
+    Projection, ProjectionError, ProjectionResult, ValueProjection,
+};
+
+/// State of min/max projections: monotonic deque of the candidates for the extremum of the
+/// windows ending at the last updated event. Events beaten by a later one are dropped in
+/// `update`, so only the candidates stay in memory. The windows which end before the event that
+/// dropped their extremum return `ProjectionError::Superseded`, the query feeds events up to the
+/// end of every window before extracting it, so this happens only for rules which resolve
+/// events later than they are passed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExtremumState<T, A> {
+    // candidates by index, with the first index of the events they have dropped
+    events: VecDeque<(Idx, T, A, Idx)>,
+    next_idx: Idx,
+    // both `start` and `end` of the windows must not decrease between calls
+    min_start: Idx,
+    min_end: Idx,
+}
+
+impl<T, A> Default for ExtremumState<T, A> {
+    fn default() -> Self {
+        ExtremumState {
+            events: VecDeque::new(),
+            next_idx: 0,
+            min_start: 0,
+            min_end: 0,
+        }
+    }
+}
+
+impl<T, A> ExtremumState<T, A> {
+    // `replaces(new, old)` tells whether the new value beats the old one. Ties must not replace,
+    // then the first occurrence of extremum is returned.
+    fn push<F>(&mut self, value: T, arg: A, replaces: F)
+    where
+        F: Fn(&T, &T) -> bool,
+    {
+        let mut dropped_from = self.next_idx;
+        while let Some((_, last, _, last_dropped_from)) = self.events.back() {
+            if !replaces(&value, last) {
+                break;
+            }
+            dropped_from = *last_dropped_from;
+            self.events.pop_back();
+        }
+        self.events
+            .push_back((self.next_idx, value, arg, dropped_from));
+        self.next_idx += 1;
+    }
+
+    fn check(&self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        if start > end {
+            Err(ProjectionError::InvalidWindow { start, end })
+        } else if start < self.min_start || end < self.min_end {
+            Err(ProjectionError::Discarded {
+                start,
+                end,
+                first_available: self.min_start,
+            })
+        } else if end >= self.next_idx {
+            Err(ProjectionError::NotUpdated {
+                start,
+                end,
+                next_idx: self.next_idx,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn discard(&mut self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        self.check(start, end)?;
+        while self.events.front().is_some_and(|(idx, ..)| *idx <= end) {
+            self.events.pop_front();
+        }
+        self.min_start = end + 1;
+        self.min_end = end + 1;
+        Ok(())
+    }
+
+    // position of the extremum of the window
+    fn position(&self, start: Idx, end: Idx) -> ProjectionResult<usize> {
+        self.check(start, end)?;
+        // the first candidate after the window must not have dropped any of its events
+        let after = self.events.partition_point(|(idx, ..)| *idx <= end);
+        if self
+            .events
+            .get(after)
+            .is_some_and(|(_, _, _, dropped_from)| *dropped_from <= end)
+        {
+            return Err(ProjectionError::Superseded { start, end });
+        }
+        // candidates are ordered from the best one, so it is the first one inside the window
+        let position = self.events.partition_point(|(idx, ..)| *idx < start);
+        if position >= after {
+            return Err(ProjectionError::Superseded { start, end });
+        }
+        Ok(position)
+    }
+
+    fn extremum(&mut self, start: Idx, end: Idx) -> ProjectionResult<(Idx, &T, &A)> {
+        let position = self.position(start, end)?;
+        self.min_start = start;
+        self.min_end = end;
+        // the next windows don't start before `start`
+        self.events.drain(..position);
+        let (idx, value, arg, _) = &self.events[0];
+        Ok((*idx, value, arg))
+    }
+
+    fn peek(&self, start: Idx, end: Idx) -> ProjectionResult<(Idx, &T, &A)> {
+        let (idx, value, arg, _) = &self.events[self.position(start, end)?];
+        Ok((*idx, value, arg))
+    }
+}
+
+macro_rules! extremum_projection {
+    ( $name:ident, $replaces:expr) => {
+        pub struct $name<E, F: Fn(&E) -> T, T>(F, PhantomData<E>, PhantomData<T>);
+        impl<E, F, T> $name<E, F, T>
+        where
+            F: Fn(&E) -> T,
+        {
+            pub fn new(field0: F) -> Self {
+                $name(field0, PhantomData, PhantomData)
+            }
+        }
+        impl<E, F: Fn(&E) -> T, T: Clone + PartialOrd> Projection for $name<E, F, T> {
+            type Event = E;
+            type State = ExtremumState<T, ()>;
+            type T = T;
+
+            fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+                events
+                    .iter()
+                    .for_each(|e| state.push(self.0(e), (), $replaces))
+            }
+
+            fn extract(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<T> {
+                Ok(state.extremum(start, end)?.1.clone())
+            }
+
+            fn discard(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<()> {
+                state.discard(start, end)
+            }
+
+            fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<T> {
+                Ok(state.peek(start, end)?.1.clone())
+            }
+        }
+        impl<E, F: Fn(&E) -> T, T: Clone + PartialOrd, V> ValueProjection<V> for $name<E, F, T> {}
+    };
+}
+
+extremum_projection!(MinProjection, |new: &T, old: &T| new < old);
+extremum_projection!(MaxProjection, |new: &T, old: &T| new > old);
+
+// Same as `Min`/`Max` projections, but returns the index of the extremum and `arg` of the
+// event where it occurred, e.g. its timestamp.
+macro_rules! arg_extremum_projection {
+    ( $name:ident, $replaces:expr) => {
+        pub struct $name<E, F: Fn(&E) -> T, T, G: Fn(&E) -> A, A>(F, G, PhantomData<(E, T, A)>);
+        impl<E, F, T, G, A> $name<E, F, T, G, A>
+        where
+            F: Fn(&E) -> T,
+            G: Fn(&E) -> A,
+        {
+            pub fn new(field0: F, arg: G) -> Self {
+                $name(field0, arg, PhantomData)
+            }
+        }
+        impl<E, F, T, G, A> Projection for $name<E, F, T, G, A>
+        where
+            F: Fn(&E) -> T,
+            T: PartialOrd,
+            G: Fn(&E) -> A,
+            A: Clone,
+        {
+            type Event = E;
+            type State = ExtremumState<T, A>;
+            type T = (Idx, A);
+
+            fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+                events
+                    .iter()
+                    .for_each(|e| state.push(self.0(e), self.1(e), $replaces))
+            }
+
+            fn extract(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<(Idx, A)> {
+                let (idx, _, arg) = state.extremum(start, end)?;
+                Ok((idx, arg.clone()))
+            }
+
+            fn discard(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<()> {
+                state.discard(start, end)
+            }
+
+            fn peek(
+                &self,
+                state: &Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<(Idx, A)> {
+                let (idx, _, arg) = state.peek(start, end)?;
+                Ok((idx, arg.clone()))
+            }
+        }
+        impl<E, F, T, G, A, V> ValueProjection<V> for $name<E, F, T, G, A>
+        where
+            F: Fn(&E) -> T,
+            T: PartialOrd,
+            G: Fn(&E) -> A,
+            A: Clone,
+        {
+        }
+    };
+}
+
+arg_extremum_projection!(ArgMinProjection, |new: &T, old: &T| new < old);
+arg_extremum_projection!(ArgMaxProjection, |new: &T, old: &T| new > old);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TE(u64, i32);
+
+    fn events() -> Vec<TE> {
+        vec![
+            TE(100, 5),
+            TE(101, 3),
+            TE(102, 8),
+            TE(103, 3),
+            TE(104, 9),
+            TE(105, 1),
+        ]
+    }
+
+    #[test]
+    fn min_max_projections() {
+        let min = MinProjection::new(|e: &TE| e.1);
+        let max = MaxProjection::new(|e: &TE| e.1);
+        let mut min_state = ExtremumState::default();
+        let mut max_state = ExtremumState::default();
+        min.update(0, &events()[..3], &mut min_state);
+        max.update(0, &events()[..3], &mut max_state);
+
+        assert_eq!(min.extract(&mut min_state, 0, 2), Ok(3));
+        assert_eq!(max.extract(&mut max_state, 0, 2), Ok(8));
+        min.update(3, &events()[3..], &mut min_state);
+        max.update(3, &events()[3..], &mut max_state);
+        assert_eq!(min.extract(&mut min_state, 3, 5), Ok(1));
+        assert_eq!(max.extract(&mut max_state, 3, 5), Ok(9));
+    }
+
+    #[test]
+    fn keeps_only_candidates() {
+        let max = MaxProjection::new(|e: &u64| *e);
+        let mut state = ExtremumState::default();
+        let events: Vec<u64> = (0..1000).collect();
+        max.update(0, &events, &mut state);
+
+        assert_eq!(state.events.len(), 1);
+        assert_eq!(max.peek(&state, 0, 999), Ok(999));
+        // the extremum of the window was dropped by the later events
+        assert_eq!(
+            max.extract(&mut state, 0, 500),
+            Err(ProjectionError::Superseded { start: 0, end: 500 })
+        );
+    }
+
+    #[test]
+    fn extract_with_same_start() {
+        let max = MaxProjection::new(|e: &TE| e.1);
+        let mut state = ExtremumState::default();
+        max.update(0, &events()[..2], &mut state);
+        assert_eq!(max.extract(&mut state, 1, 1), Ok(3));
+
+        max.update(2, &events()[2..4], &mut state);
+        assert_eq!(max.extract(&mut state, 1, 3), Ok(8));
+        assert_eq!(state.events.len(), 2);
+        max.update(4, &events()[4..], &mut state);
+        assert_eq!(max.peek(&state, 1, 4), Ok(9));
+        assert_eq!(max.extract(&mut state, 1, 5), Ok(9));
+    }
+
+    #[test]
+    fn arg_min_projection() {
+        let arg_min = ArgMinProjection::new(|e: &TE| e.1, |e: &TE| e.0);
+        let mut state = ExtremumState::default();
+        arg_min.update(0, &events()[..5], &mut state);
+        assert_eq!(arg_min.extract(&mut state, 0, 4), Ok((1, 101)));
+
+        arg_min.update(5, &events()[5..], &mut state);
+        assert_eq!(arg_min.extract(&mut state, 5, 5), Ok((5, 105)));
+    }
+}
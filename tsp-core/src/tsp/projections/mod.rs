@@ -1,5 +1,22 @@
+pub mod aggregate;
+pub mod collect;
+pub mod composite;
+pub mod extremum;
 pub mod projection;
+pub mod span;
+pub mod statistics;
 
+pub use self::aggregate::*;
+pub use self::collect::*;
+pub use self::composite::*;
+pub use self::extremum::*;
 pub use self::projection::ConstantProjection;
 pub use self::projection::FirstProjection;
+pub use self::projection::LastProjection;
+pub use self::projection::PatternValueProjection;
 pub use self::projection::Projection;
+pub use self::projection::ProjectionError;
+pub use self::projection::ProjectionResult;
+pub use self::projection::ValueProjection;
+pub use self::span::*;
+pub use self::statistics::*;
@@ -1,6 +1,9 @@
 use std::collections::VecDeque;
+use std::fmt;
 use std::marker::PhantomData;
 
+use serde::{Deserialize, Serialize};
+
 use crate::tsp::patterns::pattern::Idx;
 
 pub trait Projection {
@@ -15,13 +18,136 @@ pub trait Projection {
 
     /// Returns extracted projection for window from `start` to `end`. Also can modify `state`.
     /// The maintained invariant is that for two sequential calls of `extract` start will be not
-    /// decreasing (can be the same or greater).
-    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> Self::T;
+    /// decreasing (can be the same or greater). If the window doesn't match the state, error is
+    /// returned instead.
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<Self::T>;
+
+    /// Same as `extract`, but is called for the windows which are not reported (e.g. failures),
+    /// so the projection only forgets the events of the window.
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        self.extract(state, start, end).map(|_| ())
+    }
+
+    /// Returns the projection of the window from `start` to `end` without changing `state`, e.g.
+    /// for an incident which is not finished yet. Projections which can't compute it before the
+    /// end of the window return `ProjectionError::Unsupported`.
+    fn peek(&self, _state: &Self::State, _start: Idx, _end: Idx) -> ProjectionResult<Self::T> {
+        Err(ProjectionError::Unsupported)
+    }
+}
+
+/// Projection of the rule with results of type `V`, is used by the queries with
+/// `SimpleMachineMapper::with_pattern_values`. Is implemented for any `V` by all projections
+/// ignoring the value, `PatternValueProjection` implements it only for the type it returns, so the
+/// mismatch is reported at compile time.
+pub trait ValueProjection<V>: Projection {
+    /// Same as `extract`, but is called for the successful windows with the value of
+    /// `PatternResult::Success`, so the projection can put it into the result.
+    fn extract_with_value(
+        &self,
+        state: &mut Self::State,
+        start: Idx,
+        end: Idx,
+        _value: &V,
+    ) -> ProjectionResult<Self::T> {
+        self.extract(state, start, end)
+    }
+}
+
+/// Mismatch between the window passed to `Projection::extract` and the projection state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProjectionError {
+    /// `start` is greater than `end`.
+    InvalidWindow { start: Idx, end: Idx },
+    /// Some events of the window were already removed from the state by previous calls.
+    Discarded {
+        start: Idx,
+        end: Idx,
+        first_available: Idx,
+    },
+    /// Events up to `end` were not passed to `update` yet, `next_idx` is the first missing one.
+    NotUpdated { start: Idx, end: Idx, next_idx: Idx },
+    /// The projection doesn't implement `Projection::peek`.
+    Unsupported,
+    /// Values needed for the window were not kept because of the memory cap of the projection.
+    CapExceeded { start: Idx, end: Idx, cap: usize },
+    /// The state keeps only the values of some events and they were replaced by the later ones,
+    /// e.g. the extremum in `MinProjection`, so the window ending before them is not available.
+    Superseded { start: Idx, end: Idx },
+}
+
+impl fmt::Display for ProjectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectionError::InvalidWindow { start, end } => {
+                write!(f, "invalid window [{}, {}]", start, end)
+            }
+            ProjectionError::Discarded {
+                start,
+                end,
+                first_available,
+            } => write!(
+                f,
+                "window [{}, {}] was already discarded, first available index is {}",
+                start, end, first_available
+            ),
+            ProjectionError::NotUpdated {
+                start,
+                end,
+                next_idx,
+            } => write!(
+                f,
+                "window [{}, {}] was not updated yet, next index is {}",
+                start, end, next_idx
+            ),
+            ProjectionError::Unsupported => write!(f, "partial projection is not supported"),
+            ProjectionError::CapExceeded { start, end, cap } => write!(
+                f,
+                "window [{}, {}] doesn't fit into memory cap of {} values",
+                start, end, cap
+            ),
+            ProjectionError::Superseded { start, end } => write!(
+                f,
+                "values of window [{}, {}] were replaced by later events",
+                start, end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProjectionError {}
+
+pub type ProjectionResult<T> = Result<T, ProjectionError>;
+
+/// Checks that the window `[start, end]` lies inside of `[first_idx, next_idx)` kept in state.
+pub(crate) fn check_window(
+    start: Idx,
+    end: Idx,
+    first_idx: Idx,
+    next_idx: Idx,
+) -> ProjectionResult<()> {
+    if start > end {
+        Err(ProjectionError::InvalidWindow { start, end })
+    } else if start < first_idx {
+        Err(ProjectionError::Discarded {
+            start,
+            end,
+            first_available: first_idx,
+        })
+    } else if end >= next_idx {
+        Err(ProjectionError::NotUpdated {
+            start,
+            end,
+            next_idx,
+        })
+    } else {
+        Ok(())
+    }
 }
 
 pub struct ConstantProjection<E, T>(T, PhantomData<E>);
 
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NoProjectionState;
 
 impl<E, T: Clone> ConstantProjection<E, T> {
@@ -38,13 +164,69 @@ impl<Event, T: Clone> Projection for ConstantProjection<Event, T> {
 
     fn update(&self, _start_idx: u64, _events: &[Self::Event], _state: &mut Self::State) {}
 
-    fn extract(&self, _state: &mut Self::State, _start: Idx, _end: Idx) -> Self::T {
-        self.0.clone()
+    fn extract(&self, _state: &mut Self::State, _start: Idx, _end: Idx) -> ProjectionResult<T> {
+        Ok(self.0.clone())
+    }
+
+    fn peek(&self, _state: &Self::State, _start: Idx, _end: Idx) -> ProjectionResult<T> {
+        Ok(self.0.clone())
+    }
+}
+
+impl<Event, T: Clone, V> ValueProjection<V> for ConstantProjection<Event, T> {}
+
+/// Returns the value of `PatternResult::Success` of the window, `V` must be the type of the
+/// rule's results. Windows without a value (e.g. `PatternResult::Unknown`) give `None`, so the
+/// query must be built with `SimpleMachineMapper::with_pattern_values`.
+pub struct PatternValueProjection<E, V>(PhantomData<(E, V)>);
+
+impl<E, V> PatternValueProjection<E, V> {
+    pub fn new() -> Self {
+        PatternValueProjection(PhantomData)
+    }
+}
+
+impl<E, V> Default for PatternValueProjection<E, V> {
+    fn default() -> Self {
+        PatternValueProjection::new()
+    }
+}
+
+impl<E, V: Clone> Projection for PatternValueProjection<E, V> {
+    type Event = E;
+    type State = NoProjectionState;
+    type T = Option<V>;
+
+    fn update(&self, _start_idx: Idx, _events: &[Self::Event], _state: &mut Self::State) {}
+
+    fn extract(
+        &self,
+        _state: &mut Self::State,
+        start: Idx,
+        end: Idx,
+    ) -> ProjectionResult<Option<V>> {
+        if start > end {
+            return Err(ProjectionError::InvalidWindow { start, end });
+        }
+        Ok(None)
+    }
+}
+
+impl<E, V: Clone> ValueProjection<V> for PatternValueProjection<E, V> {
+    fn extract_with_value(
+        &self,
+        state: &mut Self::State,
+        start: Idx,
+        end: Idx,
+        value: &V,
+    ) -> ProjectionResult<Option<V>> {
+        self.extract(state, start, end)?;
+        Ok(Some(value.clone()))
     }
 }
 
 macro_rules! queue_projection {
-    ( $name:ident, $extract:expr) => {
+    ( $name:ident, $extract:expr, $peek:expr) => {
         pub struct $name<E, F: Fn(&E) -> T, T>(F, PhantomData<E>, PhantomData<T>);
         impl<E, F, T> $name<E, F, T>
         where
@@ -65,17 +247,35 @@ macro_rules! queue_projection {
                     .append(&mut events.iter().map(|x| self.0(x)).collect())
             }
 
-            fn extract(&self, state: &mut Self::State, start: u64, end: u64) -> Self::T {
-                assert!(state.queue.len() > (end - state.first_idx) as usize);
-                assert!(start <= end);
-                assert!(state.first_idx <= start);
-                $extract(state, start, end)
+            fn extract(
+                &self,
+                state: &mut Self::State,
+                start: u64,
+                end: u64,
+            ) -> ProjectionResult<T> {
+                state.check(start, end)?;
+                Ok($extract(state, start, end))
+            }
+
+            fn discard(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<()> {
+                state.discard(start, end)
+            }
+
+            fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<T> {
+                state.check(start, end)?;
+                Ok($peek(state, start, end).clone())
             }
         }
+        impl<E, F: Fn(&E) -> T, T: Clone, V> ValueProjection<V> for $name<E, F, T> {}
     };
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct QueueProjectionState<T> {
     queue: VecDeque<T>,
     first_idx: Idx,
@@ -90,25 +290,51 @@ impl<T> Default for QueueProjectionState<T> {
     }
 }
 
+// the window must be checked by `QueueProjectionState::check` before
 fn first<T: Clone>(state: &mut QueueProjectionState<T>, start: u64, end: u64) -> T {
     state.queue.drain(..(start - state.first_idx) as usize);
-    let res = state.queue.front().unwrap().clone();
+    let res = state.queue[0].clone();
     state.queue.drain(..(end - start + 1) as usize);
     state.first_idx = end + 1;
     res
 }
 
-queue_projection!(FirstProjection, first);
+fn peek_first<T>(state: &QueueProjectionState<T>, start: u64, _end: u64) -> &T {
+    &state.queue[(start - state.first_idx) as usize]
+}
+
+queue_projection!(FirstProjection, first, peek_first);
 
-fn last<T: Clone>(state: &mut QueueProjectionState<T>, start: u64, end: u64) -> T {
-    let res = state.queue.get((end - state.first_idx) as usize).unwrap().clone();
+fn last<T: Clone>(state: &mut QueueProjectionState<T>, _start: u64, end: u64) -> T {
+    let res = state.queue[(end - state.first_idx) as usize].clone();
     state.queue.drain(..(end - state.first_idx + 1) as usize);
     state.first_idx = end + 1;
     res
 }
 
-queue_projection!(LastProjection, last);
+fn peek_last<T>(state: &QueueProjectionState<T>, _start: u64, end: u64) -> &T {
+    &state.queue[(end - state.first_idx) as usize]
+}
+
+queue_projection!(LastProjection, last, peek_last);
 
+impl<T> QueueProjectionState<T> {
+    pub(crate) fn check(&self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        check_window(
+            start,
+            end,
+            self.first_idx,
+            self.first_idx + self.queue.len() as Idx,
+        )
+    }
+
+    pub(crate) fn discard(&mut self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        self.check(start, end)?;
+        self.queue.drain(..(end - self.first_idx + 1) as usize);
+        self.first_idx = end + 1;
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -130,7 +356,9 @@ mod tests {
         let constant_projection = ConstantProjection::new(expected);
         let mut updated_state = run_projection(&constant_projection, &[(0, 0), (1, 1)]);
 
-        let extracted_value = constant_projection.extract(&mut updated_state, 0, 1);
+        let extracted_value = constant_projection
+            .extract(&mut updated_state, 0, 1)
+            .unwrap();
         assert_eq!(extracted_value, expected);
         assert_eq!(updated_state, NoProjectionState);
     }
@@ -144,23 +372,79 @@ mod tests {
         let mut updated_state =
             run_projection(&first_projection, &[TE(0, 20), TE(1, 13), TE(5, 34)]);
 
-        let extracted_value = first_projection.extract(&mut updated_state, 1, 2);
+        let extracted_value = first_projection.extract(&mut updated_state, 1, 2).unwrap();
         assert_eq!(extracted_value, expected);
         assert_eq!(updated_state.first_idx, 3);
         assert!(updated_state.queue.is_empty());
     }
 
-
     #[test]
     fn last_projection() {
         let expected = 34;
         let last_projection = LastProjection::new(|e: &TE| e.1);
-        let mut updated_state =
-            run_projection(&last_projection, &[TE(0, 20), TE(1, 13), TE(2, 34), TE(3, 567)]);
+        let mut updated_state = run_projection(
+            &last_projection,
+            &[TE(0, 20), TE(1, 13), TE(2, 34), TE(3, 567)],
+        );
 
-        let extracted_value = last_projection.extract(&mut updated_state, 1, 2);
+        let extracted_value = last_projection.extract(&mut updated_state, 1, 2).unwrap();
         assert_eq!(extracted_value, expected);
         assert_eq!(updated_state.first_idx, 3);
         assert_eq!(updated_state.queue.len(), 1);
     }
+
+    #[test]
+    fn pattern_value_projection() {
+        let projection = (
+            PatternValueProjection::<TE, f64>::new(),
+            LastProjection::new(|e: &TE| e.1),
+        );
+        let mut state = Default::default();
+        projection.update(0, &[TE(0, 20), TE(1, 13)], &mut state);
+
+        assert_eq!(
+            projection.extract_with_value(&mut state, 0, 0, &83.2),
+            Ok((Some(83.2), 20))
+        );
+        assert_eq!(projection.extract(&mut state, 1, 1), Ok((None, 13)));
+    }
+
+    #[test]
+    fn discard_drops_window() {
+        let last_projection = LastProjection::new(|e: &TE| e.1);
+        let mut updated_state =
+            run_projection(&last_projection, &[TE(0, 20), TE(1, 13), TE(2, 34)]);
+
+        assert_eq!(last_projection.discard(&mut updated_state, 0, 1), Ok(()));
+        assert_eq!(updated_state.first_idx, 2);
+        assert_eq!(last_projection.extract(&mut updated_state, 2, 2), Ok(34));
+    }
+
+    #[test]
+    fn reports_window_mismatch() {
+        let first_projection = FirstProjection::new(|e: &TE| e.1);
+        let mut updated_state = run_projection(&first_projection, &[TE(0, 20), TE(1, 13)]);
+
+        assert_eq!(
+            first_projection.extract(&mut updated_state, 1, 0),
+            Err(ProjectionError::InvalidWindow { start: 1, end: 0 })
+        );
+        assert_eq!(
+            first_projection.extract(&mut updated_state, 1, 2),
+            Err(ProjectionError::NotUpdated {
+                start: 1,
+                end: 2,
+                next_idx: 2
+            })
+        );
+        assert_eq!(first_projection.extract(&mut updated_state, 1, 1), Ok(13));
+        assert_eq!(
+            first_projection.extract(&mut updated_state, 0, 1),
+            Err(ProjectionError::Discarded {
+                start: 0,
+                end: 1,
+                first_available: 2
+            })
+        );
+    }
 }
@@ -0,0 +1,229 @@
+use std::marker::PhantomData;
+use std::ops::Sub;
+
+use crate::tsp::patterns::pattern::Idx;
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::projections::projection::{
+    check_window, FirstProjection, LastProjection, Projection, ProjectionError, ProjectionResult,
+    ValueProjection,
+};
+
+/// Timestamp of the first event of the incident.
+pub type StartTsProjection<E, F, Ts> = FirstProjection<E, F, Ts>;
+
+/// Timestamp of the last event of the incident.
+pub type EndTsProjection<E, F, Ts> = LastProjection<E, F, Ts>;
+
+/// Bounds of the incident in terms of time and events.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSpan<Ts, D> {
+    pub start_ts: Ts,
+    pub end_ts: Ts,
+    pub duration: D,
+    pub event_count: u64,
+}
+
+/// State of the span projections: timestamps of the first and the last event which were passed
+/// to `update` after the last window. The window must start at the first of them and end at the
+/// last one, which is the case when the query feeds events up to the end of every window,
+/// otherwise `ProjectionError::Superseded` is returned.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpanState<Ts> {
+    first: Option<Ts>,
+    last: Option<Ts>,
+    first_idx: Idx,
+    next_idx: Idx,
+}
+
+impl<Ts> Default for SpanState<Ts> {
+    fn default() -> Self {
+        SpanState {
+            first: None,
+            last: None,
+            first_idx: 0,
+            next_idx: 0,
+        }
+    }
+}
+
+impl<Ts: Clone> SpanState<Ts> {
+    fn push(&mut self, ts: Ts) {
+        if self.next_idx == self.first_idx {
+            self.first = Some(ts.clone());
+        }
+        self.last = Some(ts);
+        self.next_idx += 1;
+    }
+
+    fn bounds(&self, start: Idx, end: Idx) -> ProjectionResult<(Ts, Ts)> {
+        check_window(start, end, self.first_idx, self.next_idx)?;
+        match (&self.first, &self.last) {
+            (Some(first), Some(last)) if start == self.first_idx && end + 1 == self.next_idx => {
+                Ok((first.clone(), last.clone()))
+            }
+            _ => Err(ProjectionError::Superseded { start, end }),
+        }
+    }
+
+    fn discard(&mut self, start: Idx, end: Idx) -> ProjectionResult<()> {
+        check_window(start, end, self.first_idx, self.next_idx)?;
+        self.first = None;
+        if end + 1 == self.next_idx {
+            self.last = None;
+        }
+        self.first_idx = end + 1;
+        Ok(())
+    }
+
+    fn extract(&mut self, start: Idx, end: Idx) -> ProjectionResult<(Ts, Ts)> {
+        let bounds = self.bounds(start, end)?;
+        self.discard(start, end)?;
+        Ok(bounds)
+    }
+}
+
+/// Returns `TimeSpan` of the window using timestamps extracted from events by `F`.
+pub struct TimeSpanProjection<E, F: Fn(&E) -> Ts, Ts>(F, PhantomData<E>, PhantomData<Ts>);
+
+impl<E, F, Ts> TimeSpanProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+{
+    pub fn new(timestamp: F) -> Self {
+        TimeSpanProjection(timestamp, PhantomData, PhantomData)
+    }
+}
+
+impl<E, F, Ts, D> Projection for TimeSpanProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+    Ts: Clone + Sub<Output = D>,
+    D: Clone,
+{
+    type Event = E;
+    type State = SpanState<Ts>;
+    type T = TimeSpan<Ts, D>;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        events.iter().for_each(|e| state.push(self.0(e)))
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<Self::T> {
+        let (start_ts, end_ts) = state.extract(start, end)?;
+        Ok(time_span(start_ts, end_ts, start, end))
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        state.discard(start, end)
+    }
+
+    fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<Self::T> {
+        let (start_ts, end_ts) = state.bounds(start, end)?;
+        Ok(time_span(start_ts, end_ts, start, end))
+    }
+}
+
+impl<E, F, Ts, D, V> ValueProjection<V> for TimeSpanProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+    Ts: Clone + Sub<Output = D>,
+    D: Clone,
+{
+}
+
+fn time_span<Ts, D>(start_ts: Ts, end_ts: Ts, start: Idx, end: Idx) -> TimeSpan<Ts, D>
+where
+    Ts: Clone + Sub<Output = D>,
+{
+    TimeSpan {
+        duration: end_ts.clone() - start_ts.clone(),
+        start_ts,
+        end_ts,
+        event_count: end - start + 1,
+    }
+}
+
+/// Wall-clock duration of the window: difference between the last and the first timestamps.
+pub struct DurationProjection<E, F: Fn(&E) -> Ts, Ts>(F, PhantomData<E>, PhantomData<Ts>);
+
+impl<E, F, Ts> DurationProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+{
+    pub fn new(timestamp: F) -> Self {
+        DurationProjection(timestamp, PhantomData, PhantomData)
+    }
+}
+
+impl<E, F, Ts, D> Projection for DurationProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+    Ts: Clone + Sub<Output = D>,
+    D: Clone,
+{
+    type Event = E;
+    type State = SpanState<Ts>;
+    type T = D;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        events.iter().for_each(|e| state.push(self.0(e)))
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<D> {
+        let (start_ts, end_ts) = state.extract(start, end)?;
+        Ok(end_ts - start_ts)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        state.discard(start, end)
+    }
+
+    fn peek(&self, state: &Self::State, start: Idx, end: Idx) -> ProjectionResult<D> {
+        let (start_ts, end_ts) = state.bounds(start, end)?;
+        Ok(end_ts - start_ts)
+    }
+}
+
+impl<E, F, Ts, D, V> ValueProjection<V> for DurationProjection<E, F, Ts>
+where
+    F: Fn(&E) -> Ts,
+    Ts: Clone + Sub<Output = D>,
+    D: Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TE(i64);
+
+    #[test]
+    fn time_span_projection() {
+        let projection = TimeSpanProjection::new(|e: &TE| e.0);
+        let mut state = SpanState::default();
+        projection.update(0, &[TE(100)], &mut state);
+        assert_eq!(projection.discard(&mut state, 0, 0), Ok(()));
+        projection.update(1, &[TE(105), TE(115)], &mut state);
+
+        let span = TimeSpan {
+            start_ts: 105,
+            end_ts: 115,
+            duration: 10,
+            event_count: 2,
+        };
+        assert_eq!(projection.peek(&state, 1, 2), Ok(span.clone()));
+        assert_eq!(projection.extract(&mut state, 1, 2), Ok(span));
+
+        let duration = DurationProjection::new(|e: &TE| e.0);
+        duration.update(3, &[TE(140)], &mut state);
+        assert_eq!(duration.extract(&mut state, 3, 3), Ok(0));
+        // only the boundaries of the window are kept
+        duration.update(4, &[TE(150), TE(160)], &mut state);
+        assert_eq!(
+            duration.extract(&mut state, 4, 4),
+            Err(ProjectionError::Superseded { start: 4, end: 4 })
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::{Add, Sub};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::patterns::pattern::Idx;
+use crate::tsp::projections::aggregate::{PrefixSumState, ToF64};
+use crate::tsp::projections::projection::{
+    check_window, Projection, ProjectionError, ProjectionResult, ValueProjection,
+};
+
+/// Count, mean and sum of squared deviations from the mean (Welford), prefix sums of these give
+/// variance of any window. Adding and subtracting them use Chan's formulas for centred moments,
+/// so big values don't cancel out as in the sum of squares.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Moments {
+    count: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn of(value: f64) -> Moments {
+        Moments {
+            count: 1.0,
+            mean: value,
+            m2: 0.0,
+        }
+    }
+}
+
+impl Add for Moments {
+    type Output = Moments;
+
+    fn add(self, other: Moments) -> Moments {
+        if self.count == 0.0 {
+            return other;
+        }
+        if other.count == 0.0 {
+            return self;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        Moments {
+            count,
+            mean: self.mean + delta * other.count / count,
+            m2: self.m2 + other.m2 + delta * delta * self.count * other.count / count,
+        }
+    }
+}
+
+impl Sub for Moments {
+    type Output = Moments;
+
+    // moments of the values which are in `self`, but not in `other`
+    fn sub(self, other: Moments) -> Moments {
+        let count = self.count - other.count;
+        if other.count == 0.0 {
+            return self;
+        }
+        if count <= 0.0 {
+            return Moments::default();
+        }
+        let mean = (self.count * self.mean - other.count * other.mean) / count;
+        let delta = mean - other.mean;
+        Moments {
+            count,
+            mean,
+            m2: (self.m2 - other.m2 - delta * delta * other.count * count / self.count).max(0.0),
+        }
+    }
+}
+
+fn variance(state: &mut PrefixSumState<Moments>, start: Idx, end: Idx) -> ProjectionResult<f64> {
+    let Moments { count, m2, .. } = state.sum(start, end)?;
+    Ok(m2 / count)
+}
+
+macro_rules! moments_projection {
+    ( $name:ident, $extract:expr) => {
+        pub struct $name<E, F: Fn(&E) -> T, T>(F, PhantomData<E>, PhantomData<T>);
+        impl<E, F, T> $name<E, F, T>
+        where
+            F: Fn(&E) -> T,
+        {
+            pub fn new(field0: F) -> Self {
+                $name(field0, PhantomData, PhantomData)
+            }
+        }
+        impl<E, F: Fn(&E) -> T, T: ToF64> Projection for $name<E, F, T> {
+            type Event = E;
+            type State = PrefixSumState<Moments>;
+            type T = f64;
+
+            fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+                events
+                    .iter()
+                    .for_each(|e| state.push(Moments::of(self.0(e).to_f64())))
+            }
+
+            fn extract(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<f64> {
+                $extract(state, start, end)
+            }
+
+            fn discard(
+                &self,
+                state: &mut Self::State,
+                start: Idx,
+                end: Idx,
+            ) -> ProjectionResult<()> {
+                state.discard(start, end)
+            }
+        }
+        impl<E, F: Fn(&E) -> T, T: ToF64, V> ValueProjection<V> for $name<E, F, T> {}
+    };
+}
+
+// population variance of the window
+moments_projection!(VarianceProjection, variance);
+moments_projection!(
+    StdDevProjection,
+    |state: &mut PrefixSumState<Moments>, start, end| variance(state, start, end).map(f64::sqrt)
+);
+
+/// Returns quantiles (`0.5` is median) of the window. The last `exact_limit` values are kept, the
+/// older values of the window are passed to P² estimators in `update`. So windows of kept values
+/// are sorted and give exact results, longer ones are estimated. The estimators can't forget a
+/// part of their values, so the window must end within the kept values if some values were passed
+/// to the estimators, otherwise the values after its end are lost and
+/// `ProjectionError::CapExceeded` is returned for the next window. The query feeds events up to
+/// the end of every window before extracting it, which keeps the end within the kept values
+/// whenever the rule resolves events as they come.
+pub struct PercentileProjection<E, F: Fn(&E) -> T, T> {
+    func: F,
+    quantiles: Vec<f64>,
+    exact_limit: usize,
+    phantom: PhantomData<(E, T)>,
+}
+
+impl<E, F, T> PercentileProjection<E, F, T>
+where
+    F: Fn(&E) -> T,
+{
+    pub fn new(func: F, quantiles: Vec<f64>, exact_limit: usize) -> Self {
+        assert!(quantiles.iter().all(|q| (0.0..=1.0).contains(q)));
+        // P² needs at least 5 values to start
+        assert!(exact_limit >= 5);
+        PercentileProjection {
+            func,
+            quantiles,
+            exact_limit,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn median(func: F, exact_limit: usize) -> Self {
+        PercentileProjection::new(func, vec![0.5], exact_limit)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PercentileState {
+    /// Values from `first_idx`, the older values of the window are in `estimators`.
+    values: VecDeque<f64>,
+    first_idx: Idx,
+    /// First index of the window which is not extracted or discarded yet.
+    window_start: Idx,
+    /// Estimators of all quantiles for the values from `window_start` to `first_idx`.
+    estimators: Vec<P2Quantile>,
+}
+
+impl PercentileState {
+    fn next_idx(&self) -> Idx {
+        self.first_idx + self.values.len() as Idx
+    }
+
+    fn forget(&mut self, end: Idx) {
+        let dropped = (end + 1).saturating_sub(self.first_idx) as usize;
+        self.values.drain(..dropped.min(self.values.len()));
+        self.first_idx = self.first_idx.max(end + 1);
+        self.window_start = end + 1;
+        self.estimators.clear();
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: ToF64> Projection for PercentileProjection<E, F, T> {
+    type Event = E;
+    type State = PercentileState;
+    type T = Vec<f64>;
+
+    fn update(&self, _start_idx: Idx, events: &[Self::Event], state: &mut Self::State) {
+        for e in events {
+            state.values.push_back((self.func)(e).to_f64());
+            if state.values.len() > self.exact_limit {
+                let value = state.values.pop_front().expect("Illegal state");
+                state.first_idx += 1;
+                if state.estimators.is_empty() {
+                    state.estimators = self.quantiles.iter().map(|q| P2Quantile::new(*q)).collect();
+                }
+                state.estimators.iter_mut().for_each(|e| e.add(value));
+            }
+        }
+    }
+
+    fn extract(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<Vec<f64>> {
+        check_window(start, end, state.window_start, state.next_idx())?;
+        let estimated = !state.estimators.is_empty();
+        // estimators can't skip their values before `start` or after `end`, values before
+        // `first_idx` are lost without them
+        let lost = if estimated {
+            start > state.window_start || end + 1 < state.first_idx
+        } else {
+            start < state.first_idx
+        };
+        if lost {
+            state.forget(end);
+            return Err(ProjectionError::CapExceeded {
+                start,
+                end,
+                cap: self.exact_limit,
+            });
+        }
+
+        let from = start.max(state.first_idx);
+        let window = state
+            .values
+            .range((from - state.first_idx) as usize..(end + 1 - state.first_idx) as usize);
+        let result = if estimated {
+            let mut estimators = state.estimators.clone();
+            for value in window {
+                estimators.iter_mut().for_each(|e| e.add(*value));
+            }
+            estimators.iter().map(|e| e.value()).collect()
+        } else {
+            let mut values: Vec<f64> = window.cloned().collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            self.quantiles
+                .iter()
+                .map(|q| exact_quantile(&values, *q))
+                .collect()
+        };
+        state.forget(end);
+        Ok(result)
+    }
+
+    fn discard(&self, state: &mut Self::State, start: Idx, end: Idx) -> ProjectionResult<()> {
+        check_window(start, end, state.window_start, state.next_idx())?;
+        state.forget(end);
+        Ok(())
+    }
+}
+
+impl<E, F: Fn(&E) -> T, T: ToF64, V> ValueProjection<V> for PercentileProjection<E, F, T> {}
+
+// linear interpolation between the closest ranks of sorted values
+fn exact_quantile(sorted: &[f64], q: f64) -> f64 {
+    let rank = q * (sorted.len() - 1) as f64;
+    let low = rank.floor() as usize;
+    let high = rank.ceil() as usize;
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+/// P² quantile estimator (Jain & Chlamtac), keeps five markers instead of the values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct P2Quantile {
+    heights: [f64; 5],
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+    count: usize,
+}
+
+impl P2Quantile {
+    fn new(q: f64) -> Self {
+        P2Quantile {
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * q, 1.0 + 4.0 * q, 3.0 + 2.0 * q, 5.0],
+            increments: [0.0, q / 2.0, q, (1.0 + q) / 2.0, 1.0],
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        // the first five values are the initial markers
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights
+                    .sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            }
+            return;
+        }
+        let h = &mut self.heights;
+        let cell = if value < h[0] {
+            h[0] = value;
+            0
+        } else if value >= h[4] {
+            h[4] = value;
+            3
+        } else {
+            (1..5).find(|i| value < h[*i]).expect("Illegal state") - 1
+        };
+        for position in self.positions.iter_mut().skip(cell + 1) {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments.iter()) {
+            *desired += increment;
+        }
+
+        for i in 1..4 {
+            let n = &self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = self.parabolic(i, d);
+                self.heights[i] =
+                    if self.heights[i - 1] < parabolic && parabolic < self.heights[i + 1] {
+                        parabolic
+                    } else {
+                        self.linear(i, d)
+                    };
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        self.heights[i]
+            + d * (self.heights[j] - self.heights[i]) / (self.positions[j] - self.positions[i])
+    }
+
+    fn value(&self) -> f64 {
+        self.heights[2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variance_projections() {
+        let projection = StdDevProjection::new(|e: &u32| *e);
+        let mut state = PrefixSumState::default();
+        projection.update(0, &[100, 2, 4, 4, 4, 5, 5, 7, 9], &mut state);
+        // the moments of the first value are subtracted, so the result is rounded
+        let std_dev = projection.extract(&mut state, 1, 8).unwrap();
+        assert!((std_dev - 2.0).abs() < 1e-9, "std dev is {}", std_dev);
+
+        // deviations are small compared to the values
+        let projection = VarianceProjection::new(|e: &f64| *e);
+        let mut state = PrefixSumState::default();
+        projection.update(
+            0,
+            &[1e9 + 4.0, 1e9 + 7.0, 1e9 + 13.0, 1e9 + 16.0],
+            &mut state,
+        );
+        assert_eq!(projection.extract(&mut state, 0, 0), Ok(0.0));
+        assert_eq!(projection.extract(&mut state, 1, 3), Ok(14.0));
+    }
+
+    #[test]
+    fn exact_percentiles() {
+        let projection = PercentileProjection::new(|e: &u32| *e, vec![0.0, 0.5, 0.9], 100);
+        let mut state = PercentileState::default();
+        projection.update(0, &[5, 1, 4, 2, 3], &mut state);
+        assert_eq!(
+            projection.extract(&mut state, 0, 4),
+            Ok(vec![1.0, 3.0, 4.6])
+        );
+    }
+
+    #[test]
+    fn approximate_percentiles() {
+        let projection = PercentileProjection::median(|e: &u32| *e, 5);
+        let mut state = PercentileState::default();
+        let events: Vec<u32> = (0..1001).map(|i| (i * 7919) % 1001).collect();
+        for (i, chunk) in events.chunks(100).enumerate() {
+            projection.update(i as Idx * 100, chunk, &mut state);
+        }
+        // only the last values and the estimators are kept
+        assert_eq!(state.values.len(), 5);
+        let median = projection.extract(&mut state, 0, 1000).unwrap()[0];
+        assert!((median - 500.0).abs() < 10.0, "median is {}", median);
+
+        projection.update(1001, &[1, 2, 3, 4, 5, 6, 7, 8], &mut state);
+        assert_eq!(
+            projection.extract(&mut state, 1001, 1002),
+            Err(ProjectionError::CapExceeded {
+                start: 1001,
+                end: 1002,
+                cap: 5
+            })
+        );
+        // the value 1003 was passed to the estimators of the failed window
+        assert!(projection.extract(&mut state, 1003, 1008).is_err());
+        projection.update(1009, &[1, 2, 3, 4], &mut state);
+        assert_eq!(projection.extract(&mut state, 1009, 1012), Ok(vec![2.5]));
+    }
+
+    #[test]
+    fn estimates_after_discard() {
+        let projection = PercentileProjection::median(|e: &u64| *e, 50);
+        let mut state = PercentileState::default();
+        let events: Vec<u64> = (0..100).collect();
+        projection.update(0, &events[..20], &mut state);
+        assert_eq!(projection.discard(&mut state, 0, 19), Ok(()));
+        projection.update(20, &events[20..], &mut state);
+        let median = projection.extract(&mut state, 20, 99).unwrap()[0];
+        assert!((median - 59.5).abs() < 5.0, "median is {}", median);
+    }
+
+    #[test]
+    fn estimates_query_windows() {
+        use crate::tsp::partitioners::NoPartitioner;
+        use crate::tsp::patterns::{AssertPattern, FunctionPattern};
+        use crate::tsp::query::SimpleMachineMapper;
+
+        // the failure window is discarded in the same chunk as the long success window
+        let events: Vec<u64> = (0..100).collect();
+        let mapper = SimpleMachineMapper::new(
+            PercentileProjection::median(|e: &&u64| **e, 50),
+            AssertPattern::new(FunctionPattern::new(|e: &&u64| **e >= 20)),
+            NoPartitioner::new(),
+        );
+        let medians: Vec<_> = mapper
+            .run(events.iter())
+            .map(|incident| incident.unwrap().projection[0])
+            .collect();
+        assert_eq!(medians.len(), 1);
+        assert!((medians[0] - 59.5).abs() < 5.0, "median is {}", medians[0]);
+    }
+}
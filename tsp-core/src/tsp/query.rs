@@ -1,8 +1,48 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
+use crate::tsp::config::EngineConfig;
 use crate::tsp::partitioners::*;
 use crate::tsp::patterns::*;
 use crate::tsp::projections::*;
+use crate::tsp::runtime::{ignore_value, ExtractValue, OutputBuffer, QueryCore};
+
+/// Tells what to do with intervals where the result of the rule is `PatternResult::Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnknownPolicy {
+    #[default]
+    Emit,
+    Skip,
+}
+
+/// Output of the query: the projection of the interval `[start_idx, end_idx]` of the partition
+/// `key` where the rule returned `result`. The indices are counted from the first event of the
+/// partition. `epoch` is the position of that event in the whole input, so it tells apart the
+/// incidents of a partition which comes back after the eviction by `EngineConfig::max_idle_events`
+/// or `EngineConfig::max_idle_time` and counts its indices from zero again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Incident<K, R: Clone, P> {
+    pub key: K,
+    pub epoch: Position,
+    pub start_idx: Idx,
+    pub end_idx: Idx,
+    pub result: PatternResult<R>,
+    pub projection: P,
+}
+
+/// Callback which is called with the key of every evicted partition, see
+/// `SimpleMachineMapper::with_on_evict`.
+pub type EvictHook<K> = Box<dyn Fn(&K) + Send + Sync>;
+
+/// Timestamp of the event used to reorder the events, see `SimpleMachineMapper::with_event_time`.
+pub type EventTime<E> = Box<dyn Fn(&E) -> u64 + Send + Sync>;
+
+/// Callback which is called with the key and the event which comes behind the watermark, see
+/// `SimpleMachineMapper::with_on_late_event`.
+pub type LateEventHook<K, E> = Box<dyn Fn(&K, &E) + Send + Sync>;
+
+/// `Incident` returned by the query with the given projection, rule and partitioner.
+pub type QueryIncident<Proj, Pat, Part> =
+    Incident<<Part as Partitioner>::T, <Pat as Pattern>::T, <Proj as Projection>::T>;
 
 pub struct SimpleMachineMapper<Proj, Pat, Part>
 where
@@ -10,9 +50,15 @@ where
     Pat: Pattern,
     Part: Partitioner,
 {
-    projection: Proj,
-    rule: Pat,
-    partitioner: Part,
+    pub(crate) projection: Proj,
+    pub(crate) extract_value: ExtractValue<Proj, Pat::T>,
+    pub(crate) rule: Pat,
+    pub(crate) partitioner: Part,
+    pub(crate) unknown_policy: UnknownPolicy,
+    pub(crate) config: EngineConfig,
+    pub(crate) on_evict: Option<EvictHook<Part::T>>,
+    pub(crate) event_time: Option<EventTime<Proj::Event>>,
+    pub(crate) on_late_event: Option<LateEventHook<Part::T, Proj::Event>>,
 }
 
 impl<Proj, Pat, Part> SimpleMachineMapper<Proj, Pat, Part>
@@ -28,8 +74,85 @@ where
     ) -> SimpleMachineMapper<Proj, Pat, Part> {
         SimpleMachineMapper {
             projection,
+            extract_value: ignore_value,
             rule,
             partitioner,
+            unknown_policy: UnknownPolicy::default(),
+            config: EngineConfig::default(),
+            on_evict: None,
+            event_time: None,
+            on_late_event: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_unknown_policy(mut self, unknown_policy: UnknownPolicy) -> Self {
+        self.unknown_policy = unknown_policy;
+        self
+    }
+
+    /// Sets the callback which is called with the key of every partition evicted according to
+    /// `EngineConfig::max_idle_events` and `EngineConfig::max_idle_time`, e.g. to release
+    /// resources of the key. It's called before the open incidents of the partition are closed
+    /// by `EvictionPolicy`.
+    pub fn with_on_evict<F>(mut self, on_evict: F) -> Self
+    where
+        F: Fn(&Part::T) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Box::new(on_evict));
+        self
+    }
+
+    /// Makes the query reorder the events of every partition by the timestamps extracted by
+    /// `event_time`, in any units, e.g. milliseconds. The watermarks and the late events are
+    /// configured by `EngineConfig::allowed_lateness`, `EngineConfig::reorder_idle_timeout` and
+    /// `EngineConfig::late_event_policy`.
+    pub fn with_event_time<F>(mut self, event_time: F) -> Self
+    where
+        F: Fn(&Proj::Event) -> u64 + Send + Sync + 'static,
+    {
+        self.event_time = Some(Box::new(event_time));
+        self
+    }
+
+    /// Sets the callback which is called with the key and the event which comes behind the
+    /// watermark of its partition when the events are reordered by `with_event_time`, e.g. to
+    /// count or store such events. It's called before the event is handled by
+    /// `EngineConfig::late_event_policy`.
+    pub fn with_on_late_event<F>(mut self, on_late_event: F) -> Self
+    where
+        F: Fn(&Part::T, &Proj::Event) + Send + Sync + 'static,
+    {
+        self.on_late_event = Some(Box::new(on_late_event));
+        self
+    }
+
+    pub(crate) fn event_order(&self) -> Option<EventOrder<'_, Part::T, Proj::Event>> {
+        self.event_time.as_ref().map(|event_time| EventOrder {
+            event_time,
+            on_late_event: self.on_late_event.as_ref(),
+            config: &self.config,
+        })
+    }
+
+    /// Makes the query pass the value of `PatternResult::Success` of every window to the
+    /// projection by `ValueProjection::extract_with_value`, e.g. for `PatternValueProjection`.
+    /// Without it the value is ignored and `Projection::extract` is called.
+    pub fn with_pattern_values(mut self) -> Self
+    where
+        Proj: ValueProjection<<Pat as Pattern>::T>,
+    {
+        self.extract_value = <Proj as ValueProjection<Pat::T>>::extract_with_value;
+        self
+    }
+
+    pub(crate) fn evicted(&self, key: &Part::T) {
+        if let Some(on_evict) = &self.on_evict {
+            on_evict(key)
         }
     }
 }
@@ -40,13 +163,15 @@ where
     Pat: Pattern<Event = Proj::Event>,
     Part: Partitioner<Event = Proj::Event>,
 {
-    pub fn run<J>(&self, events_iter: J, chunks_size: usize) -> TSPIter<Proj, Pat, Part, J>
+    pub fn run<J>(&self, events_iter: J) -> TSPIter<Proj, Pat, Part, J>
     where
         J: Iterator<Item = Proj::Event>,
     {
         TSPIter::new(
             self,
-            events_iter.partition_by(&self.partitioner, chunks_size, 1000), // todo make configurable
+            events_iter
+                .partition_by(&self.partitioner, &self.config)
+                .with_order(self.event_order()),
         )
     }
 }
@@ -60,9 +185,9 @@ where
 {
     mapper: &'a SimpleMachineMapper<Proj, Pat, Part>,
     partition_iterator: PartitionIterator<'a, J, Part>,
-    results_queues: HashMap<Part::T, PQueue<Pat::T>>,
-    projection_states: HashMap<Part::T, Proj::State>,
-    states: HashMap<Part::T, (Pat::State, Idx)>,
+    core: QueryCore<'a, Proj, Pat, Part::T>,
+    output: OutputBuffer<Part::T, QueryIncident<Proj, Pat, Part>>,
+    finished: bool,
 }
 
 impl<'a, Proj, Pat, Part, J> TSPIter<'a, Proj, Pat, Part, J>
@@ -79,9 +204,9 @@ where
         TSPIter::<'a, Proj, Pat, Part, J> {
             mapper,
             partition_iterator,
-            results_queues: HashMap::default(),
-            projection_states: HashMap::default(),
-            states: HashMap::default(),
+            core: QueryCore::new(mapper),
+            output: OutputBuffer::new(mapper.config.output_ordering()),
+            finished: false,
         }
     }
 }
@@ -93,53 +218,267 @@ where
     J: Iterator<Item = Proj::Event>,
     Part: Partitioner<Event = Proj::Event>,
 {
-    type Item = Proj::T;
+    type Item = ProjectionResult<QueryIncident<Proj, Pat, Part>>;
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             // If there is already some results we return them.
-            if !self.results_queues.is_empty() {
-                let key = self
-                    .results_queues
-                    .keys()
-                    .take(1)
-                    .next()
-                    .expect("Illegal state")
-                    .clone();
-                let results = self.results_queues.entry(key.clone()).or_default(); // this must not return empty results
-                let idx_value = results
-                    .dequeue_option()
-                    .expect("Illegal state: empty results must be deleted from results_queues!");
-
-                if results.is_empty() {
-                    self.results_queues.remove(&key);
+            let buffered = self.partition_iterator.first_buffered_position();
+            if let Some(incident) = self.output.pop(buffered, self.finished) {
+                return Some(incident);
+            }
+            if self.finished {
+                return None;
+            }
+
+            if let Some((key, chunk)) = self.partition_iterator.evict_idle() {
+                if let Some(chunk) = chunk {
+                    self.output.dispatch(&chunk.key, chunk.positions[0]);
+                    let output = self.core.process(&chunk);
+                    self.output.push(output);
+                }
+                self.mapper.evicted(&key);
+                let output = self.core.evict(key);
+                self.output.push(output);
+                continue;
+            }
+
+            // compute next batch
+            match self.partition_iterator.next() {
+                Some(chunk) => {
+                    self.output.dispatch(&chunk.key, chunk.positions[0]);
+                    let output = self.core.process(&chunk);
+                    self.output.push(output);
                 }
+                None => self.finished = true,
+            }
+        }
+    }
+}
 
-                let projection_state = self.projection_states.entry(key.clone()).or_default();
-                return Some(self.mapper.projection.extract(
-                    projection_state,
-                    idx_value.start,
-                    idx_value.end,
-                ));
-            } else {
-                // compute next batch
-                let next_batch = &self.partition_iterator.next()?;
-                let key = (&next_batch.key).clone();
-                let (state, start_idx) = self.states.entry(key.clone()).or_default();
-                self.mapper.rule.apply(
-                    *start_idx,
-                    &next_batch.elements,
-                    self.results_queues.entry(key.clone()).or_default(),
-                    state,
-                );
-                self.mapper.projection.update(
-                    *start_idx,
-                    &next_batch.elements,
-                    self.projection_states.entry(key).or_default(),
-                );
-                *start_idx += next_batch.elements.len() as u64;
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::tsp::config::{EvictionPolicy, LateEventPolicy, OutputOrdering};
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+
+    struct TE(u64, u64);
+
+    fn run(ordering: OutputOrdering) -> Vec<u64> {
+        let events = [TE(0, 0), TE(1, 1), TE(0, 2), TE(1, 3), TE(0, 4)];
+        let config = EngineConfig::builder()
+            .max_chunk_size(2)
+            .output_ordering(ordering)
+            .build()
+            .unwrap();
+        let mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+        mapper
+            .run(events.iter())
+            .map(|incident| incident.unwrap().projection)
+            .collect()
+    }
+
+    // resolves the events by pairs, so the last event of a partition can stay unresolved
+    struct PairPattern;
+
+    impl Pattern for PairPattern {
+        type State = Option<(Idx, u64)>;
+        type Event = &'static TE;
+        type T = u64;
+
+        fn apply(
+            &self,
+            start_idx: Idx,
+            events: &[Self::Event],
+            queue: &mut PQueue<u64>,
+            state: &mut Self::State,
+        ) {
+            for (idx, event) in (start_idx..).zip(events) {
+                match state.take() {
+                    Some((start, value)) => {
+                        queue.enqueue_one(IdxValue::new(start, idx, PatternResult::Success(value)));
+                    }
+                    None => *state = Some((idx, event.1)),
+                }
             }
         }
+
+        type W = Idx;
+
+        fn width(&self) -> Self::W {
+            2
+        }
+    }
+
+    static EVENTS: [TE; 7] = [
+        TE(0, 0),
+        TE(0, 1),
+        TE(0, 2),
+        TE(1, 3),
+        TE(1, 4),
+        TE(0, 5),
+        TE(0, 6),
+    ];
+
+    #[test]
+    fn evicts_idle_partitions() {
+        let run = |eviction_policy| {
+            let config = EngineConfig::builder()
+                .max_chunk_size(1)
+                .max_idle_events(2)
+                .eviction_policy(eviction_policy)
+                .build()
+                .unwrap();
+            let evicted = Arc::new(Mutex::new(vec![]));
+            let on_evict = evicted.clone();
+            let mapper = SimpleMachineMapper::new(
+                LastProjection::new(|e: &&TE| e.1),
+                PairPattern,
+                FunctionPartitioner::new(|e: &TE| e.0),
+            )
+            .with_config(config)
+            .with_on_evict(move |key| on_evict.lock().unwrap().push(*key));
+            let incidents = mapper
+                .run(EVENTS.iter())
+                .map(|incident| incident.unwrap())
+                .filter(|incident| incident.key == 0)
+                .map(|incident| {
+                    (
+                        incident.epoch,
+                        incident.start_idx,
+                        incident.result,
+                        incident.projection,
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(*evicted.lock().unwrap(), vec![0, 1]);
+            incidents
+        };
+
+        // the third event of partition 0 is closed by the eviction, then the partition starts
+        // again with the epoch of its next event
+        assert_eq!(
+            run(EvictionPolicy::Close),
+            vec![
+                (0, 0, PatternResult::Success(0), 1),
+                (0, 2, PatternResult::Unknown, 2),
+                (5, 0, PatternResult::Success(5), 6),
+            ]
+        );
+        assert_eq!(
+            run(EvictionPolicy::Drop),
+            vec![
+                (0, 0, PatternResult::Success(0), 1),
+                (5, 0, PatternResult::Success(5), 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn feeds_projection_up_to_window_end() {
+        // the maximum of the first window is dropped by the event after it in the same chunk
+        let events = [TE(0, 5), TE(0, 9), TE(0, 2)];
+        let mapper = SimpleMachineMapper::new(
+            MaxProjection::new(|e: &&TE| e.1),
+            AssertPattern::new(FunctionPattern::new(|e: &&TE| e.1 < 7)),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        );
+        let maxima: Vec<_> = mapper
+            .run(events.iter())
+            .map(|incident| incident.unwrap().projection)
+            .collect();
+        assert_eq!(maxima, vec![5, 2]);
+    }
+
+    // implements only `Projection`, so it can't get the values of the rule
+    struct LenProjection;
+
+    impl Projection for LenProjection {
+        type Event = u64;
+        type State = Idx;
+        type T = Idx;
+
+        fn update(&self, start_idx: Idx, events: &[u64], state: &mut Idx) {
+            *state = start_idx + events.len() as Idx;
+        }
+
+        fn extract(&self, _state: &mut Idx, start: Idx, end: Idx) -> ProjectionResult<Idx> {
+            Ok(end + 1 - start)
+        }
+    }
+
+    #[test]
+    fn passes_pattern_values() {
+        let events = [1u64, 1, 2, 2, 2];
+        let rule = || FunctionPattern::new(|e: &u64| *e);
+        let counts: Vec<_> = SimpleMachineMapper::new(LenProjection, rule(), NoPartitioner::new())
+            .run(events.iter().copied())
+            .map(|incident| incident.unwrap().projection)
+            .collect();
+        assert_eq!(counts, vec![2, 3]);
+
+        let mapper = SimpleMachineMapper::new(
+            (CountProjection::new(), PatternValueProjection::new()),
+            rule(),
+            NoPartitioner::new(),
+        );
+        assert!(mapper.run(events.iter().copied()).all(|incident| incident
+            .unwrap()
+            .projection
+            .1
+            .is_none()));
+        let values: Vec<_> = mapper
+            .with_pattern_values()
+            .run(events.iter().copied())
+            .map(|incident| incident.unwrap().projection)
+            .collect();
+        assert_eq!(values, vec![(2, Some(1)), (3, Some(2))]);
+    }
+
+    #[test]
+    fn reorders_events_by_time() {
+        let events = [TE(0, 2), TE(0, 1), TE(0, 3), TE(0, 0)];
+        let run = |late_event_policy| {
+            let config = EngineConfig::builder()
+                .allowed_lateness(1)
+                .late_event_policy(late_event_policy)
+                .build()
+                .unwrap();
+            let late = Arc::new(Mutex::new(vec![]));
+            let on_late_event = late.clone();
+            let mapper = SimpleMachineMapper::new(
+                LastProjection::new(|e: &&TE| e.1),
+                FunctionPattern::new(|e: &&TE| e.1),
+                FunctionPartitioner::new(|e: &TE| e.0),
+            )
+            .with_config(config)
+            .with_event_time(|e: &&TE| e.1)
+            .with_on_late_event(move |_, e: &&TE| on_late_event.lock().unwrap().push(e.1));
+            let timestamps: Vec<_> = mapper
+                .run(events.iter())
+                .map(|incident| incident.unwrap().projection)
+                .collect();
+            assert_eq!(*late.lock().unwrap(), vec![0]);
+            timestamps
+        };
+
+        // the last event is behind the watermark, which is 2 after the event 3
+        assert_eq!(run(LateEventPolicy::Drop), vec![1, 2, 3]);
+        assert_eq!(run(LateEventPolicy::Reprocess), vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn orders_output() {
+        assert_eq!(run(OutputOrdering::Completion), vec![0, 2, 1, 3, 4]);
+        assert_eq!(run(OutputOrdering::InsertionOrder), vec![0, 2, 4, 1, 3]);
+        assert_eq!(run(OutputOrdering::ByStart), vec![0, 1, 2, 3, 4]);
     }
 }
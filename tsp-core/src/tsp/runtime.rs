@@ -0,0 +1,692 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tsp::config::{EngineConfig, EvictionPolicy, OutputOrdering};
+use crate::tsp::lifecycle::{Lifecycle, LifecycleEvent};
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::{Incident, SimpleMachineMapper, UnknownPolicy};
+
+/// Key of the incident in `OutputBuffer`, incidents are returned in the order of the keys.
+pub(crate) type OrderKey = (u64, u64);
+
+/// Incidents computed from one chunk of the partition `key`.
+pub(crate) struct ChunkOutput<K, I> {
+    pub(crate) key: K,
+    pub(crate) incidents: Vec<(OrderKey, ProjectionResult<I>)>,
+    /// Position of the first event of the partition which has no result yet, is tracked only
+    /// for `OutputOrdering::ByStart`.
+    pub(crate) first_unresolved: Option<Position>,
+    /// The output is computed from a chunk passed to `OutputBuffer::dispatch`, unlike e.g. the
+    /// output of `QueryCore::evict`.
+    pub(crate) dispatched: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PartitionState<S, P, R> {
+    pattern_state: S,
+    projection_state: P,
+    next_idx: Idx,
+    // position of the first event of the partition, orders the partitions and is the epoch of
+    // its incidents
+    first_position: Position,
+    // index of the first event which has no result yet
+    first_unresolved: Idx,
+    // positions of the events from `first_unresolved` to `next_idx`, kept only for
+    // `OutputOrdering::ByStart`
+    positions: VecDeque<Position>,
+    // success interval which is not closed yet, is used only by `QueryCore::process_lifecycle`
+    open: Option<OpenInterval<R>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenInterval<R> {
+    order_key: OrderKey,
+    start: Idx,
+    end: Idx,
+    value: R,
+    // `end` when the interval was reported last time
+    reported_end: Idx,
+}
+
+impl<S, P, R> PartitionState<S, P, R> {
+    // marks the events from `start` to `end` as resolved, returns the key of their incident
+    fn resolve(&mut self, ordering: OutputOrdering, start: Idx, end: Idx) -> OrderKey {
+        let order_key = match ordering {
+            OutputOrdering::Completion => (0, 0),
+            OutputOrdering::InsertionOrder => (self.first_position, start),
+            OutputOrdering::ByStart => {
+                let start_position = self.positions[(start - self.first_unresolved) as usize];
+                self.positions
+                    .drain(..(end + 1 - self.first_unresolved) as usize);
+                (start_position, self.first_position)
+            }
+        };
+        self.first_unresolved = end + 1;
+        order_key
+    }
+
+    // the open interval is not reported yet, so it keeps the events after its start unresolved
+    fn first_unresolved_position(&self) -> Option<Position> {
+        match &self.open {
+            Some(open) => Some(open.order_key.0 + 1),
+            None => self.positions.front().copied(),
+        }
+    }
+}
+
+/// Parts of `SimpleMachineMapper` which `QueryCore` needs, the rules of `MultiQuery` share the
+/// partitioner and config.
+pub(crate) struct QuerySpec<'a, Proj, Pat>
+where
+    Proj: Projection,
+    Pat: Pattern,
+{
+    pub(crate) projection: &'a Proj,
+    pub(crate) extract_value: ExtractValue<Proj, Pat::T>,
+    pub(crate) rule: &'a Pat,
+    pub(crate) unknown_policy: UnknownPolicy,
+    pub(crate) config: &'a EngineConfig,
+}
+
+impl<Proj: Projection, Pat: Pattern> Clone for QuerySpec<'_, Proj, Pat> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Proj: Projection, Pat: Pattern> Copy for QuerySpec<'_, Proj, Pat> {}
+
+/// Extracts the projection of the window with the value of the rule, is
+/// `ValueProjection::extract_with_value` if `SimpleMachineMapper::with_pattern_values` is set and
+/// `ignore_value` otherwise.
+pub(crate) type ExtractValue<Proj, V> = fn(
+    &Proj,
+    &mut <Proj as Projection>::State,
+    Idx,
+    Idx,
+    &V,
+) -> ProjectionResult<<Proj as Projection>::T>;
+
+pub(crate) fn ignore_value<Proj: Projection, V>(
+    projection: &Proj,
+    state: &mut Proj::State,
+    start: Idx,
+    end: Idx,
+    _value: &V,
+) -> ProjectionResult<Proj::T> {
+    projection.extract(state, start, end)
+}
+
+impl<'a, Proj, Pat, Part> From<&'a SimpleMachineMapper<Proj, Pat, Part>>
+    for QuerySpec<'a, Proj, Pat>
+where
+    Proj: Projection,
+    Pat: Pattern,
+    Part: Partitioner,
+{
+    fn from(mapper: &'a SimpleMachineMapper<Proj, Pat, Part>) -> Self {
+        QuerySpec {
+            projection: &mapper.projection,
+            extract_value: mapper.extract_value,
+            rule: &mapper.rule,
+            unknown_policy: mapper.unknown_policy,
+            config: &mapper.config,
+        }
+    }
+}
+
+/// Rule and projection states of a set of partitions, turns chunks of events into incidents.
+pub(crate) struct QueryCore<'a, Proj, Pat, K>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+{
+    spec: QuerySpec<'a, Proj, Pat>,
+    partitions: Partitions<Proj, Pat, K>,
+}
+
+pub(crate) type Partitions<Proj, Pat, K> = HashMap<
+    K,
+    PartitionState<<Pat as Pattern>::State, <Proj as Projection>::State, <Pat as Pattern>::T>,
+>;
+
+type CoreState<Proj, Pat> =
+    PartitionState<<Pat as Pattern>::State, <Proj as Projection>::State, <Pat as Pattern>::T>;
+
+/// `Incident` computed by `QueryCore` with the given projection and rule.
+pub(crate) type CoreIncident<Proj, Pat, K> =
+    Incident<K, <Pat as Pattern>::T, <Proj as Projection>::T>;
+
+// state of the partition, results of the rule and the events not passed to the projection yet,
+// see `QueryCore::apply`
+type Applied<'s, 'c, Proj, Pat> = (
+    &'s mut CoreState<Proj, Pat>,
+    PQueue<<Pat as Pattern>::T>,
+    PendingEvents<'c, <Proj as Projection>::Event>,
+);
+
+/// `LifecycleEvent` computed by `QueryCore` with the given projection and rule.
+pub(crate) type CoreLifecycleEvent<Proj, Pat, K> =
+    LifecycleEvent<K, <Pat as Pattern>::T, <Proj as Projection>::T>;
+
+// `CoreLifecycleEvent` with the key which orders it in `OutputBuffer`
+type OrderedLifecycleEvent<Proj, Pat, K> =
+    (OrderKey, ProjectionResult<CoreLifecycleEvent<Proj, Pat, K>>);
+
+impl<'a, Proj, Pat, K> QueryCore<'a, Proj, Pat, K>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    K: Clone + Eq + Hash,
+{
+    pub(crate) fn new(spec: impl Into<QuerySpec<'a, Proj, Pat>>) -> Self {
+        QueryCore::with_partitions(spec, HashMap::new())
+    }
+
+    pub(crate) fn with_partitions(
+        spec: impl Into<QuerySpec<'a, Proj, Pat>>,
+        partitions: Partitions<Proj, Pat, K>,
+    ) -> Self {
+        QueryCore {
+            spec: spec.into(),
+            partitions,
+        }
+    }
+
+    pub(crate) fn partitions(&self) -> &Partitions<Proj, Pat, K> {
+        &self.partitions
+    }
+
+    pub(crate) fn process(
+        &mut self,
+        chunk: &Chunk<K, Proj::Event>,
+    ) -> ChunkOutput<K, CoreIncident<Proj, Pat, K>> {
+        let spec = self.spec;
+        let (state, mut results, mut pending) = self.apply(chunk);
+        let mut incidents = vec![];
+        while let Some(IdxValue { start, end, result }) = results.dequeue_option() {
+            pending.update_to(spec.projection, &mut state.projection_state, end);
+            let order_key = state.resolve(spec.config.output_ordering(), start, end);
+            if let Some(incident) = incident(spec, state, &chunk.key, start, end, result) {
+                incidents.push((order_key, incident));
+            }
+        }
+        pending.update_all(spec.projection, &mut state.projection_state);
+
+        ChunkOutput {
+            key: chunk.key.clone(),
+            incidents,
+            first_unresolved: state.positions.front().copied(),
+            dispatched: true,
+        }
+    }
+
+    /// Same as `process`, but the success intervals are reported by `LifecycleEvent` as soon as
+    /// they start and are closed when the rule returns a different result. Open intervals get
+    /// `Lifecycle::Updated` when `update_every` events are added to them.
+    pub(crate) fn process_lifecycle(
+        &mut self,
+        chunk: &Chunk<K, Proj::Event>,
+        update_every: Option<Idx>,
+    ) -> ChunkOutput<K, CoreLifecycleEvent<Proj, Pat, K>>
+    where
+        Pat::T: PartialEq,
+    {
+        let spec = self.spec;
+        let (state, mut results, mut pending) = self.apply(chunk);
+        let mut events = vec![];
+        while let Some(IdxValue { start, end, result }) = results.dequeue_option() {
+            pending.update_to(spec.projection, &mut state.projection_state, end);
+            let order_key = state.resolve(spec.config.output_ordering(), start, end);
+            match (&mut state.open, &result) {
+                (Some(open), PatternResult::Success(value)) if open.value == *value => {
+                    open.end = end;
+                    continue;
+                }
+                _ => {}
+            }
+            // the result has changed, so the previous interval is over
+            events.extend(close(spec, state, &chunk.key));
+            match result {
+                PatternResult::Success(value) => {
+                    events.push((
+                        order_key,
+                        Ok(LifecycleEvent {
+                            key: chunk.key.clone(),
+                            epoch: state.first_position,
+                            start_idx: start,
+                            end_idx: end,
+                            value: value.clone(),
+                            stage: Lifecycle::Opened,
+                        }),
+                    ));
+                    state.open = Some(OpenInterval {
+                        order_key,
+                        start,
+                        end,
+                        value,
+                        reported_end: end,
+                    });
+                }
+                // only the success intervals are reported
+                _ => {
+                    if let Err(e) = spec
+                        .projection
+                        .discard(&mut state.projection_state, start, end)
+                    {
+                        events.push((order_key, Err(e)));
+                    }
+                }
+            }
+        }
+
+        let epoch = state.first_position;
+        if let (Some(update_every), Some(open)) = (update_every, &mut state.open) {
+            if open.end >= open.reported_end + update_every {
+                open.reported_end = open.end;
+                let projection =
+                    spec.projection
+                        .peek(&state.projection_state, open.start, open.end);
+                events.push((
+                    open.order_key,
+                    projection.map(|projection| LifecycleEvent {
+                        key: chunk.key.clone(),
+                        epoch,
+                        start_idx: open.start,
+                        end_idx: open.end,
+                        value: open.value.clone(),
+                        stage: Lifecycle::Updated(projection),
+                    }),
+                ));
+            }
+        }
+        pending.update_all(spec.projection, &mut state.projection_state);
+
+        ChunkOutput {
+            key: chunk.key.clone(),
+            incidents: events,
+            first_unresolved: state.first_unresolved_position(),
+            dispatched: true,
+        }
+    }
+
+    /// Removes the state of the partition, its unresolved events are closed according to
+    /// `EvictionPolicy`. The output is not dispatched, but must be pushed to `OutputBuffer` after
+    /// the outputs of all chunks of the partition.
+    pub(crate) fn evict(&mut self, key: K) -> ChunkOutput<K, CoreIncident<Proj, Pat, K>> {
+        let spec = self.spec;
+        let mut incidents = vec![];
+        if let Some(mut state) = self.partitions.remove(&key) {
+            if state.first_unresolved < state.next_idx
+                && spec.config.eviction_policy() == EvictionPolicy::Close
+                && spec.unknown_policy == UnknownPolicy::Emit
+            {
+                let (start, end) = (state.first_unresolved, state.next_idx - 1);
+                let order_key = match spec.config.output_ordering() {
+                    OutputOrdering::Completion => (0, 0),
+                    OutputOrdering::InsertionOrder => (state.first_position, start),
+                    OutputOrdering::ByStart => (state.positions[0], state.first_position),
+                };
+                let result = PatternResult::Unknown;
+                if let Some(incident) = incident(spec, &mut state, &key, start, end, result) {
+                    incidents.push((order_key, incident));
+                }
+            }
+        }
+
+        ChunkOutput {
+            key,
+            incidents,
+            first_unresolved: None,
+            dispatched: false,
+        }
+    }
+
+    /// Same as `evict` for `process_lifecycle`, the open interval of the partition is closed.
+    /// The unresolved events are not reported, since they are not a success.
+    pub(crate) fn evict_lifecycle(
+        &mut self,
+        key: K,
+    ) -> ChunkOutput<K, CoreLifecycleEvent<Proj, Pat, K>> {
+        let spec = self.spec;
+        let incidents = match self.partitions.remove(&key) {
+            Some(mut state) => close(spec, &mut state, &key).into_iter().collect(),
+            None => vec![],
+        };
+        ChunkOutput {
+            key,
+            incidents,
+            first_unresolved: None,
+            dispatched: false,
+        }
+    }
+
+    /// Closes the open intervals of all partitions in the order of the partitions, no more
+    /// events are expected.
+    pub(crate) fn close_lifecycle(
+        &mut self,
+    ) -> Vec<ChunkOutput<K, CoreLifecycleEvent<Proj, Pat, K>>> {
+        let mut keys: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|(key, state)| (state.first_position, key.clone()))
+            .collect();
+        keys.sort_unstable_by_key(|(position, _)| *position);
+        keys.into_iter()
+            .map(|(_, key)| self.evict_lifecycle(key))
+            .collect()
+    }
+
+    // applies the rule to the chunk, returns its results and the events which are not passed to
+    // the projection yet
+    fn apply<'c>(&mut self, chunk: &'c Chunk<K, Proj::Event>) -> Applied<'_, 'c, Proj, Pat> {
+        let spec = self.spec;
+        let state = self
+            .partitions
+            .entry(chunk.key.clone())
+            .or_insert_with(|| PartitionState {
+                pattern_state: Default::default(),
+                projection_state: Default::default(),
+                next_idx: 0,
+                first_position: chunk.positions[0],
+                first_unresolved: 0,
+                positions: VecDeque::new(),
+                open: None,
+            });
+        if spec.config.output_ordering() == OutputOrdering::ByStart {
+            state.positions.extend(&chunk.positions);
+        }
+
+        let mut results = PQueue::default();
+        spec.rule.apply(
+            state.next_idx,
+            &chunk.elements,
+            &mut results,
+            &mut state.pattern_state,
+        );
+        let pending = PendingEvents {
+            events: &chunk.elements,
+            first_idx: state.next_idx,
+        };
+        state.next_idx += chunk.elements.len() as Idx;
+        (state, results, pending)
+    }
+}
+
+// Events of the chunk which are not passed to the projection yet. They are passed up to the end
+// of every window right before it is extracted, so the projection doesn't get the events after
+// the window when the rule resolves the events of the chunk, see `ExtremumState`.
+struct PendingEvents<'c, E> {
+    events: &'c [E],
+    first_idx: Idx,
+}
+
+impl<E> PendingEvents<'_, E> {
+    fn update_to<P>(&mut self, projection: &P, state: &mut P::State, end: Idx)
+    where
+        P: Projection<Event = E>,
+    {
+        if end >= self.first_idx {
+            let len = ((end + 1 - self.first_idx) as usize).min(self.events.len());
+            let (events, rest) = self.events.split_at(len);
+            projection.update(self.first_idx, events, state);
+            self.first_idx += len as Idx;
+            self.events = rest;
+        }
+    }
+
+    fn update_all<P>(&mut self, projection: &P, state: &mut P::State)
+    where
+        P: Projection<Event = E>,
+    {
+        if !self.events.is_empty() {
+            self.update_to(
+                projection,
+                state,
+                self.first_idx + self.events.len() as Idx - 1,
+            );
+        }
+    }
+}
+
+// Projection of the interval according to `UnknownPolicy`, `None` for the skipped intervals.
+fn incident<Proj, Pat, K>(
+    spec: QuerySpec<'_, Proj, Pat>,
+    state: &mut CoreState<Proj, Pat>,
+    key: &K,
+    start: Idx,
+    end: Idx,
+    result: PatternResult<Pat::T>,
+) -> Option<ProjectionResult<CoreIncident<Proj, Pat, K>>>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    K: Clone,
+{
+    let projection_state = &mut state.projection_state;
+    let projection = match (&result, spec.unknown_policy) {
+        // only the events of the skipped intervals are dropped from the state
+        (PatternResult::Failure, _) | (PatternResult::Unknown, UnknownPolicy::Skip) => {
+            return match spec.projection.discard(projection_state, start, end) {
+                Ok(()) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+        (PatternResult::Success(value), _) => {
+            (spec.extract_value)(spec.projection, projection_state, start, end, value)
+        }
+        _ => spec.projection.extract(projection_state, start, end),
+    };
+    Some(projection.map(|projection| Incident {
+        key: key.clone(),
+        epoch: state.first_position,
+        start_idx: start,
+        end_idx: end,
+        result,
+        projection,
+    }))
+}
+
+// Removes the open interval of the partition, returns its `Lifecycle::Closed` event.
+fn close<Proj, Pat, K>(
+    spec: QuerySpec<'_, Proj, Pat>,
+    state: &mut CoreState<Proj, Pat>,
+    key: &K,
+) -> Option<OrderedLifecycleEvent<Proj, Pat, K>>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    K: Clone,
+{
+    let OpenInterval {
+        order_key,
+        start,
+        end,
+        value,
+        ..
+    } = state.open.take()?;
+    let projection = (spec.extract_value)(
+        spec.projection,
+        &mut state.projection_state,
+        start,
+        end,
+        &value,
+    );
+    Some((
+        order_key,
+        projection.map(|projection| LifecycleEvent {
+            key: key.clone(),
+            epoch: state.first_position,
+            start_idx: start,
+            end_idx: end,
+            value,
+            stage: Lifecycle::Closed(projection),
+        }),
+    ))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Unresolved {
+    // first unresolved position of the processed chunks
+    processed: Option<Position>,
+    // first positions of the chunks which are being processed
+    in_flight: VecDeque<Position>,
+}
+
+impl Unresolved {
+    fn first(&self) -> Option<Position> {
+        self.processed.or_else(|| self.in_flight.front().copied())
+    }
+}
+
+/// Keeps computed incidents until they can be returned in the configured `OutputOrdering`.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "K: Deserialize<'de> + Eq + Hash, I: Deserialize<'de>"))]
+pub(crate) struct OutputBuffer<K, I> {
+    ordering: OutputOrdering,
+    // the incidents with equal keys, e.g. events of one lifecycle, are kept in the pushed order
+    ordered: BTreeMap<(OrderKey, u64), ProjectionResult<I>>,
+    pushed: u64,
+    // unresolved events of every partition, tracked only for `OutputOrdering::ByStart`
+    unresolved: HashMap<K, Unresolved>,
+    unresolved_positions: BTreeMap<Position, usize>,
+}
+
+impl<K: Clone + Eq + Hash, I> OutputBuffer<K, I> {
+    pub(crate) fn new(ordering: OutputOrdering) -> Self {
+        OutputBuffer {
+            ordering,
+            ordered: BTreeMap::new(),
+            pushed: 0,
+            unresolved: HashMap::new(),
+            unresolved_positions: BTreeMap::new(),
+        }
+    }
+
+    /// Must be called for every chunk before it is processed.
+    pub(crate) fn dispatch(&mut self, chunk_key: &K, first_position: Position) {
+        if self.ordering == OutputOrdering::ByStart {
+            self.update_unresolved(chunk_key, |u| u.in_flight.push_back(first_position));
+        }
+    }
+
+    /// Takes the output of the chunks in the same order as they were dispatched. The output which
+    /// is not dispatched, e.g. of an eviction, comes after all chunks of the partition dispatched
+    /// before it.
+    pub(crate) fn push(&mut self, output: ChunkOutput<K, I>) {
+        let ChunkOutput {
+            key: chunk_key,
+            incidents,
+            first_unresolved,
+            dispatched,
+        } = output;
+        for (key, incident) in incidents {
+            let key = match self.ordering {
+                OutputOrdering::Completion => (0, 0),
+                _ => key,
+            };
+            self.ordered.insert((key, self.pushed), incident);
+            self.pushed += 1;
+        }
+        if self.ordering == OutputOrdering::ByStart {
+            self.update_unresolved(&chunk_key, |u| {
+                // the chunks of a revived partition may be in flight already
+                if dispatched {
+                    u.in_flight.pop_front();
+                }
+                u.processed = first_unresolved;
+            });
+        }
+    }
+
+    /// Returns the next incident if no other incident can come before it. `buffered` is the
+    /// position of the first event which was not dispatched yet, `finished` tells that there are
+    /// no more events.
+    pub(crate) fn pop(
+        &mut self,
+        buffered: Option<Position>,
+        finished: bool,
+    ) -> Option<ProjectionResult<I>> {
+        let ((first, _), _) = self.ordered.keys().next()?;
+        let ready = finished
+            || match self.ordering {
+                OutputOrdering::Completion => true,
+                // partitions never end before the input, so only the first one is streamed
+                OutputOrdering::InsertionOrder => *first == 0,
+                OutputOrdering::ByStart => {
+                    let unresolved = self.unresolved_positions.keys().next().copied();
+                    [buffered, unresolved]
+                        .iter()
+                        .flatten()
+                        .all(|position| first < position)
+                }
+            };
+        if ready {
+            self.ordered.pop_first().map(|(_, incident)| incident)
+        } else {
+            None
+        }
+    }
+
+    fn update_unresolved<F: FnOnce(&mut Unresolved)>(&mut self, key: &K, f: F) {
+        let unresolved = self.unresolved.entry(key.clone()).or_default();
+        let old = unresolved.first();
+        f(unresolved);
+        let new = unresolved.first();
+        if new.is_none() {
+            self.unresolved.remove(key);
+        }
+        if old != new {
+            if let Some(old) = old {
+                match self.unresolved_positions.get_mut(&old) {
+                    Some(count) if *count > 1 => *count -= 1,
+                    _ => {
+                        self.unresolved_positions.remove(&old);
+                    }
+                }
+            }
+            if let Some(new) = new {
+                *self.unresolved_positions.entry(new).or_default() += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(key: u64, incidents: &[u64], dispatched: bool) -> ChunkOutput<u64, u64> {
+        ChunkOutput {
+            key,
+            incidents: incidents.iter().map(|&p| ((p, key), Ok(p))).collect(),
+            first_unresolved: None,
+            dispatched,
+        }
+    }
+
+    #[test]
+    fn eviction_keeps_revived_chunks_in_flight() {
+        let mut buffer = OutputBuffer::new(OutputOrdering::ByStart);
+        buffer.dispatch(&0, 0);
+        buffer.push(ChunkOutput {
+            first_unresolved: Some(0),
+            ..output(0, &[], true)
+        });
+        // the partition comes back before the output of its eviction
+        buffer.dispatch(&0, 5);
+        buffer.push(output(0, &[0], false));
+        buffer.dispatch(&1, 6);
+        buffer.push(output(1, &[6], true));
+
+        assert_eq!(buffer.pop(None, false), Some(Ok(0)));
+        assert_eq!(buffer.pop(None, false), None);
+        buffer.push(output(0, &[5], true));
+        assert_eq!(buffer.pop(None, false), Some(Ok(5)));
+        assert_eq!(buffer.pop(None, false), Some(Ok(6)));
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+
+use crate::tsp::engine::Engine;
+use crate::tsp::partitioners::*;
+use crate::tsp::patterns::*;
+use crate::tsp::projections::*;
+use crate::tsp::query::{QueryIncident, SimpleMachineMapper};
+
+struct StreamState<'a, Proj, Pat, Part, S>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    events: S,
+    // is taken when the input stream ends
+    engine: Option<Engine<'a, Proj, Pat, Part>>,
+    interval: tokio::time::Interval,
+    ready: VecDeque<ProjectionResult<QueryIncident<Proj, Pat, Part>>>,
+}
+
+impl<Proj, Pat, Part> SimpleMachineMapper<Proj, Pat, Part>
+where
+    Proj: Projection,
+    Pat: Pattern<Event = Proj::Event>,
+    Part: Partitioner<Event = Proj::Event>,
+{
+    /// Same as `run` for the asynchronous stream of events. Chunks are processed when they are
+    /// filled up to `EngineConfig::max_chunk_size` and also every `flush_interval`, so the
+    /// events of quiet partitions don't wait for the next events. The events are reordered the
+    /// same as by `Engine`. Must be polled inside of a tokio runtime.
+    pub fn run_stream<'a, S>(
+        &'a self,
+        events: S,
+        flush_interval: Duration,
+    ) -> impl Stream<Item = ProjectionResult<QueryIncident<Proj, Pat, Part>>> + 'a
+    where
+        S: Stream<Item = Proj::Event> + Unpin + 'a,
+    {
+        let state = StreamState {
+            events,
+            engine: Some(self.engine()),
+            interval: tokio::time::interval(flush_interval),
+            ready: VecDeque::new(),
+        };
+        futures::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(incident) = state.ready.pop_front() {
+                    return Some((incident, state));
+                }
+                let engine = state.engine.as_mut()?;
+                tokio::select! {
+                    event = state.events.next() => match event {
+                        Some(event) => {
+                            engine.push(event);
+                            state.ready.extend(engine.drain_incidents());
+                        }
+                        None => {
+                            let engine = state.engine.take().expect("Illegal state");
+                            state.ready.extend(engine.finish());
+                        }
+                    },
+                    _ = state.interval.tick() => {
+                        engine.flush();
+                        state.ready.extend(engine.drain_incidents());
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsp::config::EngineConfig;
+    use crate::tsp::partitioners::partitioner::FunctionPartitioner;
+
+    struct TE(u64, u64);
+
+    static EVENTS: [TE; 4] = [TE(0, 1), TE(1, 2), TE(0, 3), TE(1, 4)];
+
+    #[tokio::test]
+    async fn flushes_by_interval() {
+        let config = EngineConfig::builder().max_chunk_size(100).build().unwrap();
+        let mapper = SimpleMachineMapper::new(
+            LastProjection::new(|e: &&TE| e.1),
+            FunctionPattern::new(|e: &&TE| e.1),
+            FunctionPartitioner::new(|e: &TE| e.0),
+        )
+        .with_config(config);
+
+        let (sender, events) = futures::channel::mpsc::unbounded();
+        let mut incidents = Box::pin(mapper.run_stream(events, Duration::from_millis(10)));
+        sender.unbounded_send(&EVENTS[0]).unwrap();
+        // the chunk is not filled, but the interval flushes it
+        let first = incidents.next().await.unwrap().unwrap();
+        assert_eq!(first.projection, 1);
+
+        EVENTS[1..]
+            .iter()
+            .for_each(|e| sender.unbounded_send(e).unwrap());
+        drop(sender);
+        let rest: Vec<_> = incidents.map(|i| i.unwrap().projection).collect().await;
+        assert_eq!(rest.len(), 3);
+    }
+}